@@ -1,17 +1,92 @@
 #[inline]
 pub fn nop() {
     // no operations
+    #[cfg(target_arch = "aarch64")]
     unsafe { asm!("nop" :::: "volatile") };
 }
 
 #[inline]
 pub fn wfe() {
     // wait for events
+    #[cfg(target_arch = "aarch64")]
     unsafe { asm!("wfe" :::: "volatile") };
 }
 
 #[inline]
 pub fn wfi() {
     // wait for interrupts
+    #[cfg(target_arch = "aarch64")]
     unsafe { asm!("wfi" :::: "volatile") };
 }
+
+#[inline]
+pub fn counter() -> u64 {
+    // physical count of the generic timer
+    let mut count: u64 = 0;
+    #[cfg(target_arch = "aarch64")]
+    unsafe { asm!("mrs $0, CNTPCT_EL0" : "=r"(count) ::: "volatile") };
+    count
+}
+
+#[inline]
+pub fn counter_frequency() -> u64 {
+    // ticks per second of the generic timer
+    let mut frequency: u64 = 1;
+    #[cfg(target_arch = "aarch64")]
+    unsafe { asm!("mrs $0, CNTFRQ_EL0" : "=r"(frequency) ::: "volatile") };
+    frequency
+}
+
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    // x29, the frame record of the current function
+    let mut fp: usize = 0;
+    #[cfg(target_arch = "aarch64")]
+    unsafe { asm!("mov $0, x29" : "=r"(fp) ::: "volatile") };
+    fp
+}
+
+#[inline]
+pub fn core_id() -> usize {
+    // the lowest affinity level is the core number
+    let mut mpidr: u64 = 0;
+    #[cfg(target_arch = "aarch64")]
+    unsafe { asm!("mrs $0, MPIDR_EL1" : "=r"(mpidr) ::: "volatile") };
+    (mpidr & 0b11) as usize
+}
+
+#[inline]
+pub fn irq_save() -> u64 {
+    // masks the interrupts and returns the previous mask
+    let mut daif: u64 = 0;
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("mrs $0, DAIF" : "=r"(daif) ::: "volatile");
+        asm!("msr DAIFSet, #2" :::: "volatile");
+    }
+    daif
+}
+
+#[inline]
+pub fn irq_restore(daif: u64) {
+    // puts back the mask returned by irq_save
+    #[cfg(target_arch = "aarch64")]
+    unsafe { asm!("msr DAIF, $0" :: "r"(daif) :: "volatile") };
+    let _ = daif;
+}
+
+#[inline]
+pub fn timer_compare(deadline: u64) {
+    // the physical timer fires when the count reaches the deadline
+    #[cfg(target_arch = "aarch64")]
+    unsafe { asm!("msr CNTP_CVAL_EL0, $0" :: "r"(deadline) :: "volatile") };
+    let _ = deadline;
+}
+
+#[inline]
+pub fn timer_control(control: u64) {
+    // bit 0 enables the physical timer, bit 1 masks its interrupt
+    #[cfg(target_arch = "aarch64")]
+    unsafe { asm!("msr CNTP_CTL_EL0, $0" :: "r"(control) :: "volatile") };
+    let _ = control;
+}
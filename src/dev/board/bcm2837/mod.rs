@@ -5,6 +5,9 @@ use tock_registers::{registers::*, register_bitfields};
 pub const MMIO_BASE: u32 = 0x3F00_0000;
 pub const AUX_BASE: u32 = MMIO_BASE + 0x21_5000;
 pub const GPIO_BASE: u32 = MMIO_BASE + 0x20_0000;
+pub const MBOX_BASE: u32 = MMIO_BASE + 0xB880;
+// the ARM local peripherals are outside of the MMIO range
+pub const LOCAL_BASE: u32 = 0x4000_0000;
 
 register_bitfields! {
     u32,
@@ -77,6 +80,25 @@ register_bitfields! {
     ],
     AUX_MU_BAUD_REG [
         RATE OFFSET(0) NUMBITS(16) []
+    ],
+    // Mailbox
+    MBOX_STATUS [
+        FULL OFFSET(31) NUMBITS(1) [],
+        EMPTY OFFSET(30) NUMBITS(1) []
+    ],
+    // ARM local peripherals
+    CORE_TIMER_IRQCNTL [
+        CNTVIRQ OFFSET(3) NUMBITS(1) [],
+        CNTHPIRQ OFFSET(2) NUMBITS(1) [],
+        CNTPNSIRQ OFFSET(1) NUMBITS(1) [],
+        CNTPSIRQ OFFSET(0) NUMBITS(1) []
+    ],
+    CORE_IRQ_SOURCE [
+        GPU OFFSET(8) NUMBITS(1) [],
+        CNTVIRQ OFFSET(3) NUMBITS(1) [],
+        CNTHPIRQ OFFSET(2) NUMBITS(1) [],
+        CNTPNSIRQ OFFSET(1) NUMBITS(1) [],
+        CNTPSIRQ OFFSET(0) NUMBITS(1) []
     ]
 }
 
@@ -159,3 +181,40 @@ pub struct GPIO {
     __reserved_11: [u32; 4],                            // 0xA0
     __test: u32                                         // 0xB0
 }
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct MBOX {
+    pub READ: ReadOnly<u32>,                             // 0x00
+    __reserved_0: [u32; 3],                              // 0x04
+    pub PEEK: ReadOnly<u32>,                             // 0x10
+    pub SENDER: ReadOnly<u32>,                           // 0x14
+    pub STATUS: ReadOnly<u32, MBOX_STATUS::Register>,    // 0x18
+    pub CONFIG: ReadWrite<u32>,                          // 0x1C
+    pub WRITE: WriteOnly<u32>,                           // 0x20
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct LOCAL {
+    pub CONTROL: ReadWrite<u32>,                                            // 0x00
+    __reserved_0: u32,                                                      // 0x04
+    pub CORE_TIMER_PRESCALER: ReadWrite<u32>,                               // 0x08
+    pub GPU_INT_ROUTING: ReadWrite<u32>,                                    // 0x0C
+    pub PMU_INT_ROUTING_SET: WriteOnly<u32>,                                // 0x10
+    pub PMU_INT_ROUTING_CLR: WriteOnly<u32>,                                // 0x14
+    __reserved_1: u32,                                                      // 0x18
+    pub CORE_TIMER_LS: ReadWrite<u32>,                                      // 0x1C
+    pub CORE_TIMER_MS: ReadWrite<u32>,                                      // 0x20
+    pub LOCAL_INT_ROUTING: ReadWrite<u32>,                                  // 0x24
+    __reserved_2: u32,                                                      // 0x28
+    pub AXI_OUTSTANDING_COUNTERS: ReadOnly<u32>,                            // 0x2C
+    pub AXI_OUTSTANDING_IRQ: ReadWrite<u32>,                                // 0x30
+    pub LOCAL_TIMER_CONTROL: ReadWrite<u32>,                                // 0x34
+    pub LOCAL_TIMER_FLAGS: WriteOnly<u32>,                                  // 0x38
+    __reserved_3: u32,                                                      // 0x3C
+    pub CORE_TIMER_IRQCNTL: [ReadWrite<u32, CORE_TIMER_IRQCNTL::Register>; 4], // 0x40
+    pub CORE_MAILBOX_IRQCNTL: [ReadWrite<u32>; 4],                          // 0x50
+    pub CORE_IRQ_SOURCE: [ReadOnly<u32, CORE_IRQ_SOURCE::Register>; 4],     // 0x60
+    pub CORE_FIQ_SOURCE: [ReadOnly<u32>; 4],                                // 0x70
+}
@@ -0,0 +1,79 @@
+// https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface
+
+use crate::dev::board::bcm2837::*;
+use crate::asm;
+use core::ptr;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+// ARM to VideoCore property tags
+const CHANNEL_PROPERTY: u32 = 8;
+
+const REQUEST: u32 = 0x0000_0000;
+const RESPONSE_SUCCESS: u32 = 0x8000_0000;
+
+const TAG_GET_ARM_MEMORY: u32 = 0x0001_0005;
+const TAG_END: u32 = 0x0000_0000;
+
+// the firmware takes the upper 28 bits of the address, so it has to be 16 byte aligned
+#[repr(C, align(16))]
+struct Buffer([u32; 36]);
+
+pub struct Mailbox {
+    mbox: *const MBOX,
+    buffer: Buffer,
+}
+
+impl Mailbox {
+    pub const fn new() -> Mailbox {
+        Mailbox {
+            mbox: MBOX_BASE as *const MBOX,
+            buffer: Buffer([0; 36]),
+        }
+    }
+    // sends the buffer to the channel and waits for the answer
+    fn call(&mut self, channel: u32) -> bool {
+        let message = (&self.buffer as *const Buffer as u32 & !0xF) | (channel & 0xF);
+        // the buffer has to be written before the firmware gets it
+        compiler_fence(Ordering::SeqCst);
+        unsafe {
+            while (*self.mbox).STATUS.is_set(MBOX_STATUS::FULL) {
+                asm::nop();
+            }
+            (*self.mbox).WRITE.set(message);
+            loop {
+                while (*self.mbox).STATUS.is_set(MBOX_STATUS::EMPTY) {
+                    asm::nop();
+                }
+                // answers to other channels are dropped
+                if (*self.mbox).READ.get() == message {
+                    compiler_fence(Ordering::SeqCst);
+                    return self.word(1) == RESPONSE_SUCCESS;
+                }
+            }
+        }
+    }
+    // base address and size of the memory the ARM core owns (the rest belongs to the GPU)
+    pub fn arm_memory(&mut self) -> Option<(usize, usize)> {
+        let b = &mut self.buffer.0;
+        b[0] = 8 * 4;
+        b[1] = REQUEST;
+        b[2] = TAG_GET_ARM_MEMORY;
+        // value buffer size
+        b[3] = 8;
+        b[4] = REQUEST;
+        // base address
+        b[5] = 0;
+        // size in bytes
+        b[6] = 0;
+        b[7] = TAG_END;
+        if !self.call(CHANNEL_PROPERTY) {
+            return None;
+        }
+        Some((self.word(5) as usize, self.word(6) as usize))
+    }
+    // the firmware writes the buffer behind the compiler's back
+    #[inline]
+    fn word(&self, i: usize) -> u32 {
+        unsafe { ptr::read_volatile(&self.buffer.0[i]) }
+    }
+}
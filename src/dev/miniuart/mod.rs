@@ -3,22 +3,32 @@ use core::fmt::Write;
 use core::option::Option;
 use crate::dev::board::bcm2837::*;
 use crate::asm;
+use crate::sys::reactor::deferred::wake;
+use crate::sys::reactor::source::*;
 
 pub struct MiniUart {
     aux: *const AUX,
     gpio: *const GPIO,
     pub input: Option<VecDeque<u8>>,
+    // the loop waits for the transmitter
+    tx_wait: bool,
 }
 
+// only reached through the lock of global![mini_uart]
+unsafe impl Send for MiniUart {}
+
 impl MiniUart { 
     pub const fn new() -> MiniUart {
         MiniUart {
             aux: AUX_BASE as *const AUX,
             gpio: GPIO_BASE as *const GPIO,
-            input: None
+            input: None,
+            tx_wait: false
         }
     }
-    pub fn init(&self) {
+    pub fn init(&mut self) {
+        self.input = Some(VecDeque::with_capacity(255));
+
         unsafe {
             // Enable UART module (and not touching other enabled modules)
             (*self.aux).AUX_ENABLES.modify(AUX_ENABLES::MINI_UART_ENABLE::SET);
@@ -127,21 +137,31 @@ impl MiniUart {
         unsafe {
             (*self.aux).AUX_MU_IER_REG.write(
                 AUX_MU_IER_REG::INTERRUPT_ENABLE::SET +
-                AUX_MU_IER_REG::INTERRUPT_EMPTY::CLEAR +
+                AUX_MU_IER_REG::INTERRUPT_EMPTY.val(self.tx_wait as u32) +
                 AUX_MU_IER_REG::INTERRUPT_HAS_BYTE::SET
             );
         }
     }
+    // interrupt context: hands the received bytes to the loop, nothing is
+    // allocated here
     #[inline]
     pub fn try_read_char(&mut self) {
-        if self.input.is_none() {
-            self.input = Some(VecDeque::with_capacity(255));
-        }
-        let input_queue = self.input.as_mut().unwrap();
         unsafe {
-            if (*self.aux).AUX_MU_LSR_REG.is_set(AUX_MU_LSR_REG::DATA_READY) {
+            while (*self.aux).AUX_MU_LSR_REG.is_set(AUX_MU_LSR_REG::DATA_READY) {
                 let c = (*self.aux).AUX_MU_IO_REG.get() as u8;
-                input_queue.push_back(c);
+                // dropped if the loop is that far behind, the queue counts it
+                global![deferred].defer(receive, u64::from(c));
+            }
+        }
+    }
+    // interrupt context: the transmitter can take more, the loop is woken up
+    #[inline]
+    pub fn try_wake_writer(&mut self) {
+        unsafe {
+            if self.tx_wait && (*self.aux).AUX_MU_LSR_REG.is_set(AUX_MU_LSR_REG::TRANSMIT_EMPTY) {
+                self.tx_wait = false;
+                self.interrupt_enable();
+                global![deferred].defer(wake, 0);
             }
         }
     }
@@ -169,6 +189,13 @@ impl MiniUart {
     }
 }
 
+// thread context, the bottom half of try_read_char
+fn receive(c: u64) {
+    global![mini_uart].lock().input.as_mut().unwrap().push_back(c as u8);
+    // the readers get it in the next pass
+    global![default_loop].wake();
+}
+
 impl Write for MiniUart {
     fn write_char(&mut self, c: char) -> core::fmt::Result {
         self.interrupt_disable();
@@ -183,13 +210,28 @@ impl Write for MiniUart {
         self.interrupt_enable();
         Ok(())
     }
+    // the line discipline maps the line endings
     fn write_str(&mut self, input: &str) -> core::fmt::Result {
         for c in input.chars() {
-            if c == '\n' {
-                self.write_char('\r')?;
-            }
             self.write_char(c)?;
         }
         Ok(())
     }
 }
+
+impl EventSource for MiniUart {
+    fn ready(&self) -> bool {
+        self.character_available()
+    }
+    fn poll(&mut self) -> Option<Event> {
+        self.try_get_char().map(Event::Char)
+    }
+    fn try_put(&mut self, c: char) -> bool {
+        self.try_put_char(c)
+    }
+    fn notify_writable(&mut self) -> bool {
+        self.tx_wait = true;
+        self.interrupt_enable();
+        true
+    }
+}
@@ -1,2 +1,4 @@
+pub mod mailbox;
 pub mod miniuart;
+pub mod timer;
 pub mod board;
\ No newline at end of file
@@ -0,0 +1,68 @@
+// the non-secure physical timer of the ARM generic timer, its interrupt is
+// routed to core 0 by the local peripherals
+
+use alloc::prelude::v1::*;
+use core::fmt::Write;
+use crate::dev::board::bcm2837::*;
+use crate::asm;
+use crate::sys::shell::{Builtin, ShellError};
+
+const ENABLE: u64 = 1 << 0;
+
+pub struct Timer {
+    local: *const LOCAL,
+    // the tick the timer is armed for
+    deadline: Option<u64>,
+}
+
+impl Timer {
+    pub const fn new() -> Timer {
+        Timer {
+            local: LOCAL_BASE as *const LOCAL,
+            deadline: None,
+        }
+    }
+    pub fn init(&mut self) {
+        self.disarm();
+        global![shell].register(Box::new(Builtin {
+            name: "uptime",
+            help: "uptime, the time since the counter started",
+            run: uptime,
+        }));
+    }
+    #[inline]
+    pub fn interrupt_enable(&self) {
+        unsafe {
+            (*self.local).CORE_TIMER_IRQCNTL[0].write(CORE_TIMER_IRQCNTL::CNTPNSIRQ::SET);
+        }
+    }
+    #[inline]
+    pub fn now(&self) -> u64 {
+        asm::counter()
+    }
+    // the ticks in the given milliseconds
+    pub fn ticks(&self, ms: u64) -> u64 {
+        ms * asm::counter_frequency() / 1000
+    }
+    // fires the interrupt once the counter reaches the deadline, a deadline in
+    // the past fires right away
+    pub fn arm(&mut self, deadline: u64) {
+        if self.deadline == Some(deadline) {
+            return;
+        }
+        self.deadline = Some(deadline);
+        asm::timer_compare(deadline);
+        asm::timer_control(ENABLE);
+    }
+    // the interrupt stays asserted until the timer is disabled
+    pub fn disarm(&mut self) {
+        self.deadline = None;
+        asm::timer_control(0);
+    }
+}
+
+fn uptime(_: &[String], s: &mut String) -> Result<(), ShellError> {
+    let ms = asm::counter() * 1000 / asm::counter_frequency();
+    writeln!(s, "{}.{:03} s", ms / 1000, ms % 1000)?;
+    Ok(())
+}
@@ -1,20 +1,43 @@
 use crate::sys::reactor::*;
+use crate::sys::reactor::deferred::*;
+use crate::sys::reactor::executor::*;
+use crate::dev::mailbox::*;
 use crate::dev::miniuart::*;
+use crate::dev::timer::*;
 use crate::sys::alloc::*;
 use crate::ALLOCATOR;
 use crate::sys::exception::interrupt::*;
+use crate::sys::shell::Shell;
+use crate::sys::sync::SpinLock;
 
-static mut MINIUART: MiniUart = MiniUart::new();
-static mut DEFAULT_LOOP: Loop = Loop::new();
+static mut MAILBOX: Mailbox = Mailbox::new();
+static MINIUART: SpinLock<MiniUart> = SpinLock::new(MiniUart::new());
+static mut TIMER: Timer = Timer::new();
+static DEFAULT_LOOP: Loop = Loop::new();
+static EXECUTOR: Executor = Executor::new();
+static DEFERRED: Deferred = Deferred::new();
 static mut INTERRUPT: Interrupt = Interrupt::new();
+static SHELL: Shell = Shell::new();
 
-register_global!(mini_uart, MiniUart, MINIUART);
-register_global!(default_loop, Loop, DEFAULT_LOOP);
+register_global!(mailbox, Mailbox, MAILBOX);
+register_static!(mini_uart, SpinLock<MiniUart>, MINIUART);
+register_global!(timer, Timer, TIMER);
+register_static!(default_loop, Loop, DEFAULT_LOOP);
+register_static!(executor, Executor, EXECUTOR);
+register_static!(deferred, Deferred, DEFERRED);
 register_global!(allocator, Allocator, ALLOCATOR);
 register_global!(interrupt, Interrupt, INTERRUPT);
+register_static!(shell, Shell, SHELL);
 
 pub fn init() {
+    // the other cores stay parked, the spin locks only mask the interrupts
+    // until they are released with sync::enable_multi_core
     global![allocator].init();
-    global![mini_uart].init();
+    // before the others, they register their commands
+    global![shell].init();
+    crate::sys::alloc::commands::register();
+    global![mini_uart].lock().init();
+    global![timer].init();
     global![default_loop].init();
+    global![executor].init();
 }
@@ -28,8 +28,37 @@ macro_rules! register_global {
     );
 }
 
+// for the globals that are shared, they take care of their own mutability
+#[macro_export]
+macro_rules! register_static {
+    ($name:ident, $type:path, $variable_name:ident) => (
+        #[inline]
+        pub fn $name() -> &'static $type {
+            return &$crate::globals::$variable_name;
+        }
+    );
+}
+
+#[cfg(not(test))]
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    global![mini_uart].write_fmt(args).unwrap();
-}
\ No newline at end of file
+    use crate::sys::reactor::discipline::Output;
+    use crate::sys::reactor::source::CONSOLE;
+    let discipline = global![default_loop].discipline(CONSOLE);
+    // the lock keeps the loop and the interrupts off the device until the
+    // whole of it is written
+    let mut mini_uart = global![mini_uart].lock();
+    let mut output = Output {
+        discipline,
+        device: &mut *mini_uart,
+    };
+    output.write_fmt(args).unwrap();
+}
+
+// the tests run on the host, without the console
+#[cfg(test)]
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    std::print!("{}", args);
+}
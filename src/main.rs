@@ -1,12 +1,16 @@
-#![no_std]
-#![no_main]
+// the tests run on the host, with std
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 #![feature(format_args_nl)]
 #![feature(allocator_api)]
-#![feature(alloc)]
+#![feature(alloc_prelude)]
 #![feature(alloc_error_handler)]
 #![feature(global_asm)]
 #![feature(asm)]
+#![feature(async_await)]
 
+#[cfg(not(test))]
 global_asm!(include_str!("boot/start.S"));
 
 #[macro_use]
@@ -17,15 +21,25 @@ mod dev;
 mod sys;
 mod globals;
 
-use alloc::prelude::*;
+use alloc::prelude::v1::*;
+use alloc::rc::Rc;
 use core::panic::PanicInfo;
 use sys::alloc::*;
+use sys::reactor::ansi::*;
+use sys::reactor::channel::*;
+use sys::reactor::io::*;
+use sys::reactor::line_editor::*;
 
 extern crate alloc;
 
-#[global_allocator]
+#[cfg_attr(not(test), global_allocator)]
 static mut ALLOCATOR: Allocator = Allocator::new();
 
+#[cfg(test)]
+#[global_allocator]
+static COUNTING: counting::Counting = counting::Counting;
+
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     use crate::asm;
@@ -35,41 +49,81 @@ fn panic(info: &PanicInfo) -> ! {
     }
 }
 
-fn echo() {
-    global![default_loop].read_char(Box::new(|c| {
-        if c != '\n' {
-            global![default_loop].put_char(c, Box::new(|| {}));
+// how long the console waits when it is out of memory, in milliseconds
+const RETRY_DELAY: u64 = 1000;
+
+// reads the lines for the shell, the next prompt waits until the shell is done
+// with the line, the console is the only one feeding it
+async fn console(lines: Sender<String>, done: Receiver<()>) -> Result<(), AllocError> {
+    let options = LineOptions {
+        prompt: "> ".to_string(),
+        completions: Some(Rc::new(|line: &str, start| global![shell].complete(line, start))),
+    };
+    write_str("Welcome!\n").await?;
+    loop {
+        let line = match read_line_with(options.clone()).await {
+            Ok(line) => line,
+            // the memory may be freed in the meantime
+            Err(e) => {
+                print!("\n[shell] {}\n", e);
+                sleep(RETRY_DELAY).await?;
+                continue;
+            },
+        };
+        // the shell is gone
+        if send(&lines, line).await?.is_err() || recv(&done).await?.is_none() {
+            return Ok(());
         }
-        echo();
-    }));
+    }
 }
 
-fn command_line() {
-    global![default_loop].read_line(Box::new(|line| {
-        use core::fmt::Write;
-        // echo back, for now
+// the lines go to the commands of the shell
+async fn shell(lines: Receiver<String>, done: Sender<()>) -> Result<(), AllocError> {
+    use core::fmt::Write;
+    while let Some(line) = recv(&lines).await? {
         let mut s = String::new();
-        write!(s, "\n{}\n> ", line).unwrap();
-        global![default_loop].put_string(s, Box::new(command_line));
-    }));
+        if let Err(e) = global![shell].execute(&line, &mut s) {
+            writeln!(s, "{}", Style::new().fg(Color::Red).paint(e)).unwrap();
+        }
+        // printing does not allocate, it still works when memory is low
+        if let Err(e) = write_str(&s).await {
+            print!("\n[shell] {}\n", e);
+        }
+        if send(&done, ()).await?.is_err() {
+            break;
+        }
+    }
+    Ok(())
 }
 
+#[cfg(not(test))]
 #[no_mangle]
 extern "C" fn _main() -> ! {
     globals::init();
 
     global![interrupt].interrupt_enable();
 
-    global![default_loop].put_string(
-        "Welcome!\n> ".to_string(),
-        Box::new(|| {
-            echo();
-            command_line();
+    // first, so a pass sees the ops the tasks add in the same run
+    global![executor].spawn(global![default_loop].driver());
+    // the console hands the lines to the shell, the shell tells it when the
+    // output of a line is written
+    let (lines, receiver) = spsc(1);
+    let (done, finished) = spsc(1);
+    global![executor].spawn(async move {
+        if let Err(e) = console(lines, finished).await {
+            println!("\n[console] {}, stopped", e);
+        }
+    });
+    global![executor].spawn(async move {
+        if let Err(e) = shell(receiver, done).await {
+            println!("\n[shell] {}, stopped", e);
         }
-    ));
+    });
 
     loop {
-        global![default_loop].run();
-        asm::wfi();
+        // the loop is a task as well, the interrupts wake it through the
+        // deferred queue and its callbacks wake the other tasks
+        global![executor].run();
+        global![executor].idle();
     }
 }
@@ -0,0 +1,333 @@
+use core::alloc::Layout;
+use super::heap::Heap;
+use super::slab::{ClassStats, Slab, CLASSES};
+
+#[derive(Clone, Copy)]
+pub struct HeapStats {
+    // bytes given to the arena
+    pub size: usize,
+    // bytes requested by the live allocations
+    pub in_use: usize,
+    pub peak: usize,
+    // free bytes in the heap and in the slabs
+    pub free: usize,
+    // the part of it in the heap, the slabs do not fragment
+    pub heap_free: usize,
+    pub largest_free: usize,
+    pub allocations: usize,
+    pub live: usize,
+}
+
+impl HeapStats {
+    // adds up the stats of two arenas, the peak becomes the sum of the peaks
+    pub fn merge(&mut self, other: &HeapStats) {
+        self.size += other.size;
+        self.in_use += other.in_use;
+        self.peak += other.peak;
+        self.free += other.free;
+        self.heap_free += other.heap_free;
+        self.largest_free = self.largest_free.max(other.largest_free);
+        self.allocations += other.allocations;
+        self.live += other.live;
+    }
+    // the percentage of the free heap that is not in the largest block
+    pub fn fragmentation(&self) -> usize {
+        if self.heap_free == 0 {
+            return 0;
+        }
+        100 - self.largest_free * 100 / self.heap_free
+    }
+}
+
+impl core::fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "\
+            heap          {:>10} B\n\
+            in use        {:>10} B\n\
+            peak          {:>10} B\n\
+            free          {:>10} B\n\
+            largest free  {:>10} B\n\
+            fragmentation {:>10} %\n\
+            allocations   {:>10} ({} live)\
+            ",
+            self.size,
+            self.in_use,
+            self.peak,
+            self.free,
+            self.largest_free,
+            self.fragmentation(),
+            self.allocations,
+            self.live
+        )
+    }
+}
+
+// the allocation logic over the regions it is given, small layouts are served
+// from the size-class slabs, the rest from the heap
+pub struct Arena {
+    heap: Heap,
+    slab: Slab,
+    size: usize,
+    in_use: usize,
+    peak: usize,
+    allocations: usize,
+    live: usize,
+}
+
+impl Arena {
+    pub const fn new() -> Arena {
+        Arena {
+            heap: Heap::empty(),
+            slab: Slab::new(),
+            size: 0,
+            in_use: 0,
+            peak: 0,
+            allocations: 0,
+            live: 0,
+        }
+    }
+    // the memory between start and start + size must be unused and must stay
+    // valid for the lifetime of the arena
+    pub unsafe fn add_region(&mut self, start: usize, size: usize) {
+        self.heap.add_region(start, size);
+        self.size += size;
+    }
+    // null if the arena is out of memory, the padding is the part of the
+    // layout the caller did not ask for, it is not counted as in use
+    pub fn allocate(&mut self, layout: Layout, padding: usize) -> *mut u8 {
+        let ptr = match Slab::class_of(layout) {
+            Some(class) => self.slab.allocate(class, &mut self.heap),
+            None => self.heap.allocate(layout),
+        };
+        if !ptr.is_null() {
+            self.account_alloc(layout.size() - padding);
+        }
+        ptr
+    }
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout, padding: usize) {
+        match Slab::class_of(layout) {
+            Some(class) => self.slab.deallocate(class, ptr),
+            None => self.heap.deallocate(ptr, layout),
+        }
+        self.account_dealloc(layout.size() - padding);
+    }
+    // false if the allocation has to be moved
+    #[cfg(any(test, not(feature = "debug-heap")))]
+    pub unsafe fn resize_in_place(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let resized = match (Slab::class_of(layout), Slab::class_of(new_layout)) {
+            (Some(old), Some(new)) => old == new,
+            (None, None) => self.heap.reallocate_in_place(ptr, layout, new_size),
+            _ => false,
+        };
+        if resized {
+            self.account_resize(layout.size(), new_size);
+        }
+        resized
+    }
+    // the free bytes in the heap and in the slabs, without walking the heap
+    pub fn free(&self) -> usize {
+        self.heap.free() + self.slab.free_space()
+    }
+    pub fn stats(&self) -> HeapStats {
+        let (free, largest_free) = self.heap.free_space();
+        HeapStats {
+            size: self.size,
+            in_use: self.in_use,
+            peak: self.peak,
+            free: free + self.slab.free_space(),
+            heap_free: free,
+            largest_free,
+            allocations: self.allocations,
+            live: self.live,
+        }
+    }
+    pub fn slab_stats(&self) -> [ClassStats; CLASSES] {
+        self.slab.stats()
+    }
+    // forgets all of the regions, false if something is still allocated from
+    // them, the counters are kept
+    pub fn clear(&mut self) -> bool {
+        if self.live != 0 {
+            return false;
+        }
+        self.heap = Heap::empty();
+        self.slab = Slab::new();
+        self.size = 0;
+        true
+    }
+    fn account_alloc(&mut self, size: usize) {
+        self.in_use += size;
+        self.peak = self.peak.max(self.in_use);
+        self.allocations += 1;
+        self.live += 1;
+    }
+    fn account_dealloc(&mut self, size: usize) {
+        self.in_use -= size;
+        self.live -= 1;
+    }
+    // the same allocation, it is not counted again
+    #[cfg(any(test, not(feature = "debug-heap")))]
+    fn account_resize(&mut self, size: usize, new_size: usize) {
+        self.in_use = self.in_use - size + new_size;
+        self.peak = self.peak.max(self.in_use);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 1 << 20;
+
+    // xorshift, the tests have to be reproducible
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 as usize
+        }
+    }
+
+    fn arena(memory: &mut Vec<u64>) -> Arena {
+        let mut arena = Arena::new();
+        unsafe {
+            arena.add_region(memory.as_mut_ptr() as usize, memory.len() * 8);
+        }
+        arena
+    }
+
+    #[test]
+    fn random_alloc_free() {
+        let mut memory = vec![0u64; SIZE / 8];
+        let mut arena = arena(&mut memory);
+        let mut random = Random(0x2545_F491_4F6C_DD1D);
+        let mut live: Vec<(*mut u8, Layout, u8)> = Vec::new();
+        for i in 0..50_000 {
+            let action = random.next() % 4;
+            if action < 2 || live.is_empty() {
+                let size = random.next() % 3000;
+                let align = 1 << (random.next() % 8);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = arena.allocate(layout, 0);
+                if ptr.is_null() {
+                    continue;
+                }
+                assert_eq!(ptr as usize % align, 0);
+                let tag = i as u8;
+                unsafe {
+                    core::ptr::write_bytes(ptr, tag, size);
+                }
+                live.push((ptr, layout, tag));
+            } else {
+                let (ptr, layout, tag) = live.swap_remove(random.next() % live.len());
+                unsafe {
+                    // nobody else wrote into the block
+                    for j in 0..layout.size() {
+                        assert_eq!(*ptr.add(j), tag);
+                    }
+                    if action == 2 {
+                        let new_size = random.next() % 3000;
+                        if arena.resize_in_place(ptr, layout, new_size) {
+                            let layout = Layout::from_size_align(new_size, layout.align()).unwrap();
+                            core::ptr::write_bytes(ptr, tag, new_size);
+                            live.push((ptr, layout, tag));
+                            continue;
+                        }
+                    }
+                    arena.deallocate(ptr, layout, 0);
+                }
+            }
+        }
+        for (ptr, layout, _) in live.drain(..) {
+            unsafe {
+                arena.deallocate(ptr, layout, 0);
+            }
+        }
+        let stats = arena.stats();
+        assert_eq!(stats.in_use, 0);
+        assert_eq!(stats.live, 0);
+    }
+
+    #[test]
+    fn alignment() {
+        let mut memory = vec![0u64; SIZE / 8];
+        let mut arena = arena(&mut memory);
+        for &size in [0, 1, 15, 16, 17, 1023, 1024, 1025, 4096, 5000].iter() {
+            for shift in 0..13 {
+                let layout = Layout::from_size_align(size, 1 << shift).unwrap();
+                let ptr = arena.allocate(layout, 0);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % (1 << shift), 0);
+                unsafe {
+                    arena.deallocate(ptr, layout, 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut memory = vec![0u64; SIZE / 8];
+        let mut arena = arena(&mut memory);
+        let layout = Layout::from_size_align(2048, 8).unwrap();
+        let mut blocks = Vec::new();
+        loop {
+            let ptr = arena.allocate(layout, 0);
+            if ptr.is_null() {
+                break;
+            }
+            blocks.push(ptr);
+        }
+        assert!(blocks.len() > SIZE / 2048 - 4);
+        assert!(arena.allocate(Layout::from_size_align(16, 8).unwrap(), 0).is_null());
+        for ptr in blocks.drain(..) {
+            unsafe {
+                arena.deallocate(ptr, layout, 0);
+            }
+        }
+        // everything is merged back into one block
+        assert_eq!(arena.stats().largest_free, arena.stats().free);
+        assert!(!arena.allocate(Layout::from_size_align(SIZE / 2, 8).unwrap(), 0).is_null());
+    }
+
+    #[test]
+    fn stats() {
+        let mut memory = vec![0u64; SIZE / 8];
+        // the slab is carved from the start, the free heap stays in one block
+        let start = (memory.as_mut_ptr() as usize + 4095) & !4095;
+        let mut arena = Arena::new();
+        unsafe {
+            arena.add_region(start, SIZE / 2);
+        }
+        let small = Layout::from_size_align(16, 8).unwrap();
+        let object = arena.allocate(small, 0);
+        let layout = Layout::from_size_align(2000, 8).unwrap();
+        let ptr = arena.allocate(layout, 0);
+        unsafe {
+            arena.deallocate(object, small, 0);
+            assert!(arena.resize_in_place(ptr, layout, 3000));
+        }
+        // the red zones of debug-heap are not in use
+        let padded = arena.allocate(layout, 48);
+        let stats = arena.stats();
+        assert_eq!((stats.in_use, stats.allocations, stats.live), (3000 + 1952, 3, 2));
+        assert!(stats.free > stats.heap_free);
+        assert_eq!(stats.largest_free, stats.heap_free);
+        assert_eq!(stats.fragmentation(), 0);
+        // only once everything is freed
+        assert!(!arena.clear());
+        unsafe {
+            arena.deallocate(ptr, Layout::from_size_align(3000, 8).unwrap(), 0);
+            arena.deallocate(padded, layout, 48);
+        }
+        assert!(arena.clear());
+        assert_eq!(arena.stats().size, 0);
+        assert!(arena.allocate(small, 0).is_null());
+    }
+}
@@ -0,0 +1,45 @@
+// the shell commands of the allocators
+
+use alloc::prelude::v1::*;
+use core::fmt::Write;
+use crate::sys::shell::{Builtin, ShellError};
+
+pub fn register() {
+    global![shell].register(Box::new(Builtin {
+        name: "meminfo",
+        help: "meminfo, the heap, the free pages and the slabs",
+        run: meminfo,
+    }));
+    global![shell].register(Box::new(Builtin {
+        name: "leaks",
+        help: "leaks, the live allocations by call site, with trace-heap",
+        run: leaks,
+    }));
+}
+
+fn meminfo(_: &[String], s: &mut String) -> Result<(), ShellError> {
+    // not holding the lock while the string allocates
+    let (available, total) = global![allocator].pages();
+    writeln!(s, "{}", global![allocator].stats())?;
+    writeln!(s, "pages         {:>10} / {} free", available, total)?;
+    let reserve = if global![allocator].is_low() { "in use" } else { "held back" };
+    writeln!(s, "reserve       {:>10} B {}", super::RESERVE_PAGES * super::PAGE_SIZE, reserve)?;
+    writeln!(s, "slabs")?;
+    for class in global![allocator].slab_stats().iter() {
+        writeln!(s, "  {}", class)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "trace-heap")]
+fn leaks(_: &[String], s: &mut String) -> Result<(), ShellError> {
+    let summary = global![allocator].leaks();
+    write!(s, "{}", summary)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "trace-heap"))]
+fn leaks(_: &[String], s: &mut String) -> Result<(), ShellError> {
+    writeln!(s, "allocation tracing is off, build with --features trace-heap")?;
+    Ok(())
+}
@@ -0,0 +1,32 @@
+// the tests run on the system allocator, wrapped to count the allocations of
+// each thread, so a test can tell whether the code under it allocates
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+pub struct Counting;
+
+thread_local!(static ALLOCATIONS: Cell<usize> = Cell::new(0));
+
+// the allocations of the calling thread so far, the reallocations included
+pub fn allocations() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+fn count() {
+    // the thread may be going away
+    let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, size)
+    }
+}
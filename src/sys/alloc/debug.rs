@@ -0,0 +1,162 @@
+// debug heap: every allocation is wrapped in red zones and carries a header
+//
+//   | canary ... | magic | size | user data ... | canary |
+//   ^ block      ^ user - 16    ^ user
+//
+// freed memory is poisoned and the header is kept as FREED until the block is
+// reused, so double frees are caught as long as nothing else got the block
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use super::Allocator;
+
+const MAGIC_LIVE: usize = 0x4C49_5645_A110_C8ED;
+const MAGIC_FREED: usize = 0xF4EE_DF4E_EDF4_EED0;
+
+const CANARY: u8 = 0xAB;
+// fresh allocations, catches reads of uninitialised memory
+const POISON_ALLOC: u8 = 0xCD;
+// freed memory, catches use after free
+const POISON_FREE: u8 = 0xDD;
+
+const HEADER_SIZE: usize = 16;
+// the space after the user data
+const BACK_ZONE: usize = 16;
+
+#[repr(C)]
+struct Header {
+    magic: usize,
+    size: usize,
+}
+
+// the red zone before the data, it holds the header and at least 16 bytes of
+// canary and keeps the data aligned
+#[inline]
+fn front_zone(layout: Layout) -> usize {
+    layout.align().max(2 * HEADER_SIZE)
+}
+
+#[inline]
+unsafe fn raw_layout(layout: Layout) -> Layout {
+    Layout::from_size_align_unchecked(
+        front_zone(layout) + layout.size() + BACK_ZONE,
+        layout.align().max(HEADER_SIZE),
+    )
+}
+
+#[inline]
+unsafe fn header(ptr: *mut u8) -> *mut Header {
+    ptr.sub(HEADER_SIZE) as *mut Header
+}
+
+// the first corrupted byte of the zone
+unsafe fn check_zone(start: *const u8, len: usize) -> Option<usize> {
+    (0..len).find(|&i| *start.add(i) != CANARY)
+}
+
+// checks the header and the red zones, returns false if the block must not be
+// given back to the heap
+unsafe fn check(ptr: *mut u8, layout: Layout) -> bool {
+    if ptr.is_null() || ptr as usize % layout.align() != 0 {
+        println!(
+            "[heap] free of foreign pointer {:#010X}, size {:#X}, {:?}",
+            ptr as usize,
+            layout.size(),
+            layout
+        );
+        return false;
+    }
+    let h = header(ptr);
+    match (*h).magic {
+        MAGIC_LIVE => {},
+        MAGIC_FREED => {
+            println!(
+                "[heap] double free of {:#010X}, size {:#X}, {:?}",
+                ptr as usize,
+                layout.size(),
+                layout
+            );
+            return false;
+        },
+        _ => {
+            println!(
+                "[heap] free of foreign pointer {:#010X}, size {:#X}, {:?}",
+                ptr as usize,
+                layout.size(),
+                layout
+            );
+            return false;
+        }
+    }
+    if (*h).size != layout.size() {
+        println!(
+            "[heap] layout mismatch at {:#010X}, allocated size {:#X}, freed with {:?}",
+            ptr as usize,
+            (*h).size,
+            layout
+        );
+        return false;
+    }
+    let front = front_zone(layout) - HEADER_SIZE;
+    if let Some(i) = check_zone(ptr.sub(front_zone(layout)), front) {
+        println!(
+            "[heap] underflow at {:#010X} ({} bytes before), size {:#X}, {:?}",
+            ptr as usize,
+            front + HEADER_SIZE - i,
+            layout.size(),
+            layout
+        );
+    }
+    if let Some(i) = check_zone(ptr.add(layout.size()), BACK_ZONE) {
+        println!(
+            "[heap] overflow at {:#010X} ({} bytes after), size {:#X}, {:?}",
+            ptr as usize,
+            i,
+            layout.size(),
+            layout
+        );
+    }
+    true
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let raw = raw_layout(layout);
+        let block = self.allocate(raw, raw.size() - layout.size());
+        if block.is_null() {
+            return block;
+        }
+        let front = front_zone(layout);
+        let ptr = block.add(front);
+        ptr::write_bytes(block, CANARY, front - HEADER_SIZE);
+        header(ptr).write(Header {
+            magic: MAGIC_LIVE,
+            size: layout.size(),
+        });
+        ptr::write_bytes(ptr, POISON_ALLOC, layout.size());
+        ptr::write_bytes(ptr.add(layout.size()), CANARY, BACK_ZONE);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // a broken block is leaked, handing it back would spread the damage
+        if !check(ptr, layout) {
+            return;
+        }
+        (*header(ptr)).magic = MAGIC_FREED;
+        ptr::write_bytes(ptr, POISON_FREE, layout.size());
+        let raw = raw_layout(layout);
+        self.deallocate(ptr.sub(front_zone(layout)), raw, raw.size() - layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // always moving, so stale pointers to the old block are caught
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
@@ -0,0 +1,60 @@
+// allocation helpers that report running out of memory instead of ending up
+// in the alloc_error_handler
+
+use alloc::prelude::v1::*;
+use core::alloc::Layout;
+use core::mem;
+
+#[derive(Clone, Copy, Debug)]
+pub struct AllocError {
+    pub layout: Layout,
+}
+
+impl core::fmt::Display for AllocError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "out of memory allocating {} bytes (align {})",
+            self.layout.size(),
+            self.layout.align()
+        )
+    }
+}
+
+// raw memory from the global allocator, null is turned into an error
+fn try_alloc(layout: Layout) -> Result<*mut u8, AllocError> {
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    if ptr.is_null() {
+        return Err(AllocError { layout });
+    }
+    Ok(ptr)
+}
+
+pub fn try_box<T>(value: T) -> Result<Box<T>, AllocError> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        return Ok(Box::new(value));
+    }
+    let ptr = try_alloc(layout)? as *mut T;
+    unsafe {
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+pub fn try_vec<T>(capacity: usize) -> Result<Vec<T>, AllocError> {
+    if capacity == 0 || mem::size_of::<T>() == 0 {
+        return Ok(Vec::new());
+    }
+    let layout = Layout::array::<T>(capacity).map_err(|_| AllocError {
+        layout: Layout::new::<T>(),
+    })?;
+    let ptr = try_alloc(layout)? as *mut T;
+    unsafe { Ok(Vec::from_raw_parts(ptr, 0, capacity)) }
+}
+
+pub fn try_string(s: &str) -> Result<String, AllocError> {
+    let mut bytes = try_vec(s.len())?;
+    bytes.extend_from_slice(s.as_bytes());
+    unsafe { Ok(String::from_utf8_unchecked(bytes)) }
+}
@@ -0,0 +1,261 @@
+use core::ptr;
+
+pub const PAGE_SIZE: usize = 4096;
+
+// blocks from 1 page (order 0) up to 512 MiB (order 17)
+pub const ORDERS: usize = 18;
+
+// header of a free block, stored in its first page
+struct FreeFrame {
+    next: *mut FreeFrame,
+}
+
+#[inline]
+fn block_size(order: usize) -> usize {
+    PAGE_SIZE << order
+}
+
+// the smallest order holding the given number of pages
+#[inline]
+pub fn order_of(pages: usize) -> usize {
+    let mut order = 0;
+    while (1 << order) < pages {
+        order += 1;
+    }
+    order
+}
+
+// buddy allocator of physical pages, a block of order n is 2^n pages and is
+// aligned to its own size
+pub struct FrameAllocator {
+    free: [*mut FreeFrame; ORDERS],
+    // the length of each list
+    blocks: [usize; ORDERS],
+    total: usize,
+    available: usize,
+}
+
+impl FrameAllocator {
+    pub const fn new() -> FrameAllocator {
+        FrameAllocator {
+            free: [ptr::null_mut(); ORDERS],
+            blocks: [0; ORDERS],
+            total: 0,
+            available: 0,
+        }
+    }
+    // adds the region without the reserved (start, end) ranges, which must be
+    // sorted by address
+    pub unsafe fn add_region_reserved(
+        &mut self,
+        start: usize,
+        end: usize,
+        reserved: &[(usize, usize)],
+    ) {
+        let mut start = start;
+        for &(r_start, r_end) in reserved {
+            if r_end <= start || r_start >= end {
+                continue;
+            }
+            if r_start > start {
+                self.add_region(start, r_start);
+            }
+            start = start.max(r_end);
+        }
+        if start < end {
+            self.add_region(start, end);
+        }
+    }
+    // hands the pages between start and end to the allocator, partial pages
+    // at the edges are left alone
+    pub unsafe fn add_region(&mut self, start: usize, end: usize) {
+        let mut start = (start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let end = end & !(PAGE_SIZE - 1);
+        while start < end {
+            // the biggest block that is aligned and still fits
+            let mut order = ORDERS - 1;
+            while start & (block_size(order) - 1) != 0 || start + block_size(order) > end {
+                order -= 1;
+            }
+            self.total += 1 << order;
+            self.free(start, order);
+            start += block_size(order);
+        }
+    }
+    // a block of 2^order pages, aligned to its size
+    pub fn alloc(&mut self, order: usize) -> Option<usize> {
+        let mut current = (order..ORDERS).find(|&o| !self.free[o].is_null())?;
+        let block = self.pop(current);
+        // splitting, the upper halves go back to the lists
+        while current > order {
+            current -= 1;
+            self.push(block + block_size(current), current);
+        }
+        self.available -= 1 << order;
+        Some(block)
+    }
+    // the block must come from `alloc` with the same order
+    pub unsafe fn free(&mut self, addr: usize, order: usize) {
+        self.available += 1 << order;
+        let mut addr = addr;
+        let mut order = order;
+        // merging with the buddy as long as it is free
+        while order < ORDERS - 1 {
+            let buddy = addr ^ block_size(order);
+            if !self.remove(buddy, order) {
+                break;
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+    // at least `pages` contiguous pages, aligned to `align` bytes
+    pub fn alloc_pages(&mut self, pages: usize, align: usize) -> Option<usize> {
+        let order = order_of(pages).max(order_of(align / PAGE_SIZE));
+        self.alloc(order)
+    }
+    // `pages` and `align` must be the ones passed to `alloc_pages`, the heap
+    // does not give pages back yet
+    #[cfg(test)]
+    pub unsafe fn free_pages(&mut self, addr: usize, pages: usize, align: usize) {
+        let order = order_of(pages).max(order_of(align / PAGE_SIZE));
+        self.free(addr, order);
+    }
+    pub fn total_pages(&self) -> usize {
+        self.total
+    }
+    pub fn available_pages(&self) -> usize {
+        self.available
+    }
+    // the free pages in blocks of at least 2^order pages
+    pub fn available_pages_of(&self, order: usize) -> usize {
+        (order..ORDERS).map(|order| self.blocks[order] << order).sum()
+    }
+    fn push(&mut self, addr: usize, order: usize) {
+        let frame = addr as *mut FreeFrame;
+        unsafe {
+            (*frame).next = self.free[order];
+        }
+        self.free[order] = frame;
+        self.blocks[order] += 1;
+    }
+    fn pop(&mut self, order: usize) -> usize {
+        let frame = self.free[order];
+        self.free[order] = unsafe { (*frame).next };
+        self.blocks[order] -= 1;
+        frame as usize
+    }
+    // unlinks the block from the list of the order if it is there
+    fn remove(&mut self, addr: usize, order: usize) -> bool {
+        let mut link: *mut *mut FreeFrame = &mut self.free[order];
+        unsafe {
+            while !(*link).is_null() {
+                if *link as usize == addr {
+                    *link = (**link).next;
+                    self.blocks[order] -= 1;
+                    return true;
+                }
+                link = &mut (**link).next;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGES: usize = 1024;
+
+    // the region is aligned to its size, so it ends up as a single block
+    fn frames(memory: &mut Vec<u8>) -> (FrameAllocator, usize) {
+        let size = PAGES * PAGE_SIZE;
+        let start = (memory.as_mut_ptr() as usize + size - 1) & !(size - 1);
+        let mut frames = FrameAllocator::new();
+        unsafe {
+            frames.add_region(start, start + size);
+        }
+        (frames, start)
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let mut memory = vec![0u8; 2 * PAGES * PAGE_SIZE];
+        let (mut frames, start) = frames(&mut memory);
+        assert_eq!(frames.total_pages(), PAGES);
+        let mut blocks = Vec::new();
+        let mut seed = 0x9E37_79B9_7F4A_7C15u64;
+        for _ in 0..5000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            if seed % 3 != 0 || blocks.is_empty() {
+                let pages = 1 + (seed >> 8) as usize % 20;
+                let align = PAGE_SIZE << ((seed >> 16) % 5);
+                if let Some(addr) = frames.alloc_pages(pages, align) {
+                    assert_eq!(addr % align, 0);
+                    assert!(addr >= start && addr + pages * PAGE_SIZE <= start + PAGES * PAGE_SIZE);
+                    // no overlap with the live blocks
+                    let size = PAGE_SIZE << order_of(pages).max(order_of(align / PAGE_SIZE));
+                    for &(other, other_size, _, _) in blocks.iter() {
+                        assert!(addr + size <= other || other + other_size <= addr);
+                    }
+                    blocks.push((addr, size, pages, align));
+                }
+            } else {
+                let (addr, _, pages, align) = blocks.swap_remove((seed >> 8) as usize % blocks.len());
+                unsafe {
+                    frames.free_pages(addr, pages, align);
+                }
+            }
+        }
+        for (addr, _, pages, align) in blocks.drain(..) {
+            unsafe {
+                frames.free_pages(addr, pages, align);
+            }
+        }
+        assert_eq!(frames.available_pages(), PAGES);
+        // the buddies are merged back to a single block
+        assert_eq!(frames.alloc_pages(PAGES, PAGE_SIZE), Some(start));
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut memory = vec![0u8; 2 * PAGES * PAGE_SIZE];
+        let (mut frames, _) = frames(&mut memory);
+        let mut pages = Vec::new();
+        while let Some(addr) = frames.alloc(0) {
+            pages.push(addr);
+        }
+        assert_eq!(pages.len(), PAGES);
+        assert_eq!(frames.available_pages(), 0);
+        assert_eq!(frames.alloc(0), None);
+        unsafe {
+            frames.free(pages[0], 0);
+        }
+        assert_eq!(frames.alloc(1), None);
+        assert_eq!((frames.available_pages_of(0), frames.available_pages_of(1)), (1, 0));
+        assert_eq!(frames.alloc(0), Some(pages[0]));
+    }
+
+    #[test]
+    fn reserved_ranges() {
+        let mut memory = vec![0u8; 2 * PAGES * PAGE_SIZE];
+        let (_, start) = frames(&mut memory);
+        let mut frames = FrameAllocator::new();
+        let reserved = [
+            (start - PAGE_SIZE, start + 2 * PAGE_SIZE),
+            (start + 10 * PAGE_SIZE, start + 12 * PAGE_SIZE),
+        ];
+        unsafe {
+            frames.add_region_reserved(start, start + 64 * PAGE_SIZE, &reserved);
+        }
+        assert_eq!(frames.total_pages(), 64 - 2 - 2);
+        while let Some(addr) = frames.alloc(0) {
+            assert!(addr >= start + 2 * PAGE_SIZE);
+            assert!(addr < start + 10 * PAGE_SIZE || addr >= start + 12 * PAGE_SIZE);
+        }
+    }
+}
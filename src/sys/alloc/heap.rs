@@ -0,0 +1,244 @@
+use core::alloc::Layout;
+use core::mem;
+use core::ptr;
+
+// header of a free block, stored in the free memory itself
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+// every block is at least this big and aligned to this, so a split never leaves
+// a fragment that could not hold its own header
+const BLOCK_SIZE: usize = mem::size_of::<FreeBlock>();
+
+#[inline]
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+#[inline]
+fn align_down(addr: usize, align: usize) -> usize {
+    addr & !(align - 1)
+}
+
+// the real size of the block backing an allocation
+#[inline]
+fn block_size(size: usize) -> usize {
+    if size == 0 {
+        return BLOCK_SIZE;
+    }
+    align_up(size, BLOCK_SIZE)
+}
+
+// first-fit free-list heap, the free blocks are kept in address order so the
+// neighbours can be merged on free
+pub struct Heap {
+    // dummy node, only `next` is used
+    head: FreeBlock,
+    // the bytes in the list, kept up to date so they are not counted
+    free: usize,
+}
+
+impl Heap {
+    pub const fn empty() -> Heap {
+        Heap {
+            head: FreeBlock {
+                size: 0,
+                next: ptr::null_mut(),
+            },
+            free: 0,
+        }
+    }
+    // the memory between start and start + size must be unused and must stay
+    // valid for the lifetime of the heap
+    pub unsafe fn add_region(&mut self, start: usize, size: usize) {
+        let end = align_down(start + size, BLOCK_SIZE);
+        let start = align_up(start, BLOCK_SIZE);
+        if end > start && end - start >= BLOCK_SIZE {
+            self.free_region(start, end - start);
+        }
+    }
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let size = block_size(layout.size());
+        let align = layout.align().max(BLOCK_SIZE);
+        let mut prev: *mut FreeBlock = &mut self.head;
+        unsafe {
+            while !(*prev).next.is_null() {
+                let block = (*prev).next;
+                let start = block as usize;
+                let end = start + (*block).size;
+                let addr = align_up(start, align);
+                if addr < end && end - addr >= size {
+                    let next = (*block).next;
+                    // the front and the back of the block go back to the list,
+                    // both are multiples of BLOCK_SIZE so they can hold a header
+                    let mut link = prev;
+                    if addr > start {
+                        (*block).size = addr - start;
+                        (*link).next = block;
+                        link = block;
+                    }
+                    if addr + size < end {
+                        let back = (addr + size) as *mut FreeBlock;
+                        back.write(FreeBlock {
+                            size: end - addr - size,
+                            next: ptr::null_mut(),
+                        });
+                        (*link).next = back;
+                        link = back;
+                    }
+                    (*link).next = next;
+                    self.free -= size;
+                    return addr as *mut u8;
+                }
+                prev = block;
+            }
+        }
+        ptr::null_mut()
+    }
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        self.free_region(ptr as usize, block_size(layout.size()));
+    }
+    // grows or shrinks the allocation without moving it, returns false if the
+    // memory after the block is not free
+    #[cfg(any(test, not(feature = "debug-heap")))]
+    pub unsafe fn reallocate_in_place(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> bool {
+        let start = ptr as usize;
+        let old = block_size(layout.size());
+        let new = block_size(new_size);
+        if new <= old {
+            if new < old {
+                self.free_region(start + new, old - new);
+            }
+            return true;
+        }
+        // looking for a free block right after the allocation
+        let wanted = new - old;
+        let mut prev: *mut FreeBlock = &mut self.head;
+        while !(*prev).next.is_null() && ((*prev).next as usize) < start + old {
+            prev = (*prev).next;
+        }
+        let block = (*prev).next;
+        if block.is_null() || block as usize != start + old || (*block).size < wanted {
+            return false;
+        }
+        let next = (*block).next;
+        let rest = (*block).size - wanted;
+        if rest == 0 {
+            (*prev).next = next;
+        } else {
+            let moved = (start + new) as *mut FreeBlock;
+            moved.write(FreeBlock { size: rest, next });
+            (*prev).next = moved;
+        }
+        self.free -= wanted;
+        true
+    }
+    pub fn free(&self) -> usize {
+        self.free
+    }
+    // the free bytes and the biggest free block
+    pub fn free_space(&self) -> (usize, usize) {
+        let mut free = 0;
+        let mut largest = 0;
+        let mut block = self.head.next;
+        while !block.is_null() {
+            unsafe {
+                free += (*block).size;
+                largest = largest.max((*block).size);
+                block = (*block).next;
+            }
+        }
+        (free, largest)
+    }
+    // puts the region back to the list, merging it with the neighbours
+    unsafe fn free_region(&mut self, start: usize, size: usize) {
+        self.free += size;
+        let head: *mut FreeBlock = &mut self.head;
+        let mut prev = head;
+        while !(*prev).next.is_null() && ((*prev).next as usize) < start {
+            prev = (*prev).next;
+        }
+        let mut next = (*prev).next;
+        let mut size = size;
+        if !next.is_null() && start + size == next as usize {
+            size += (*next).size;
+            next = (*next).next;
+        }
+        if prev != head && prev as usize + (*prev).size == start {
+            (*prev).size += size;
+            (*prev).next = next;
+        } else {
+            let block = start as *mut FreeBlock;
+            block.write(FreeBlock { size, next });
+            (*prev).next = block;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 64 * 1024;
+
+    fn heap(memory: &mut Vec<u64>) -> (Heap, usize) {
+        let mut heap = Heap::empty();
+        let start = align_up(memory.as_mut_ptr() as usize, BLOCK_SIZE);
+        unsafe {
+            heap.add_region(start, SIZE);
+        }
+        (heap, start)
+    }
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 8).unwrap()
+    }
+
+    #[test]
+    fn coalescing() {
+        let mut memory = vec![0u64; SIZE / 8 + 2];
+        let (mut heap, start) = heap(&mut memory);
+        let blocks: Vec<*mut u8> = (0..8).map(|_| heap.allocate(layout(SIZE / 8))).collect();
+        assert_eq!(blocks[0] as usize, start);
+        assert!(heap.allocate(layout(1)).is_null());
+        assert_eq!(heap.free(), 0);
+        // freeing in an order that needs merging with both neighbours
+        for &i in [1, 3, 2, 0, 7, 5, 6, 4].iter() {
+            unsafe {
+                heap.deallocate(blocks[i], layout(SIZE / 8));
+            }
+        }
+        assert_eq!(heap.free_space(), (SIZE, SIZE));
+        assert_eq!(heap.free(), SIZE);
+    }
+
+    #[test]
+    fn reallocate_in_place() {
+        let mut memory = vec![0u64; SIZE / 8 + 2];
+        let (mut heap, _) = heap(&mut memory);
+        let a = heap.allocate(layout(100));
+        let b = heap.allocate(layout(100));
+        unsafe {
+            // b is in the way
+            assert!(!heap.reallocate_in_place(a, layout(100), 200));
+            // shrinking always works, the tail is free again
+            assert!(heap.reallocate_in_place(a, layout(100), 32));
+            assert!(heap.reallocate_in_place(a, layout(32), 112));
+            assert_eq!(heap.free(), heap.free_space().0);
+            heap.deallocate(a, layout(112));
+            // growing into the free tail of the heap
+            assert!(heap.reallocate_in_place(b, layout(100), SIZE - 112));
+            assert!(!heap.reallocate_in_place(b, layout(SIZE - 112), SIZE));
+            heap.deallocate(b, layout(SIZE - 112));
+        }
+        assert_eq!(heap.free_space(), (SIZE, SIZE));
+        assert_eq!(heap.free(), SIZE);
+    }
+}
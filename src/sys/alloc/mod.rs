@@ -1,57 +1,455 @@
+// the allocation logic in arena, frame, heap and slab works on any memory
+// region and is tested on the host, only this file knows about the board
+mod arena;
+pub mod commands;
+#[cfg(test)]
+pub mod counting;
+#[cfg(feature = "debug-heap")]
+mod debug;
+mod fallible;
+mod frame;
+mod heap;
+mod slab;
+#[cfg(feature = "trace-heap")]
+mod trace;
 
-use core::alloc::{Layout, GlobalAlloc};
-use core::cell::Cell;
+#[cfg(not(feature = "debug-heap"))]
+use core::alloc::GlobalAlloc;
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::asm;
+use crate::sys::sync::SpinLock;
+use self::arena::Arena;
+use self::frame::FrameAllocator;
+use self::slab::CLASSES;
 
+pub use self::arena::HeapStats;
+pub use self::fallible::{AllocError, try_box, try_string, try_vec};
+pub use self::frame::PAGE_SIZE;
+pub use self::slab::ClassStats;
+
+// the heap starts with this many pages and grows by at least as much
+const HEAP_PAGES: usize = 256;
+// held back from the start and given to the heap when everything else is gone,
+// so there is still memory to report the problem and to recover, it is taken
+// back once nothing is allocated from it
+const RESERVE_PAGES: usize = 16;
+
+#[cfg(not(test))]
 #[alloc_error_handler]
-fn alloc_error_handler(_layout: Layout) -> ! {
+fn alloc_error_handler(layout: Layout) -> ! {
+    // printing does not allocate
+    println!("\n[alloc] {}", AllocError { layout });
+    println!("{}", global![allocator].stats());
+    let (available, total) = global![allocator].pages();
+    println!("pages         {:>10} / {} free", available, total);
     panic!("ALLOC_ERROR")
 }
 
+const CORES: usize = 4;
+// the arena of the emergency reserve, the last resort of all the cores
+const EMERGENCY: usize = CORES;
+const ARENAS: usize = CORES + 1;
+
+// the heap is given to the arenas in blocks of at least 1 MiB, aligned to
+// their size, so the owner of a pointer can be looked up by its MiB
+const GRANULE_SHIFT: usize = 20;
+const GRANULES: usize = 1 << (30 - GRANULE_SHIFT);
+
+// an arena for each core, all of them growing from the frame allocator, a
+// core out of memory takes from the arenas of the others and then from the
+// emergency reserve, the frees are returned to the owning arena
 pub struct Allocator {
-    next: Cell<usize>,
-    end: Cell<usize>,
+    frames: SpinLock<FrameAllocator>,
+    arenas: [SpinLock<Arena>; ARENAS],
+    // what each arena has free, updated under its lock, so the headroom is
+    // checked without taking the locks
+    free: [AtomicUsize; ARENAS],
+    // the index + 1 of the arena owning each granule from base, 0 if it is
+    // not heap, written under the lock of the frames before the arena gets
+    // the memory and never changed after
+    owners: UnsafeCell<[u8; GRANULES]>,
+    base: usize,
+    // where the emergency reserve lies, its pages belong to its arena while
+    // the rest of their granule can go to the others, 0 without a reserve
+    reserve_start: usize,
+    // the emergency reserve, 0 while its arena has it
+    reserve: AtomicUsize,
+    // the reserve in its arena, 0 while it is held back
+    spent: AtomicUsize,
+    #[cfg(feature = "trace-heap")]
+    tracer: SpinLock<trace::Tracer>,
 }
 
 impl Allocator {
     pub const fn new() -> Allocator {
         Allocator {
-            next: Cell::new(0),
-            end: Cell::new(0),
+            frames: SpinLock::new(FrameAllocator::new()),
+            arenas: [
+                SpinLock::new(Arena::new()),
+                SpinLock::new(Arena::new()),
+                SpinLock::new(Arena::new()),
+                SpinLock::new(Arena::new()),
+                SpinLock::new(Arena::new()),
+            ],
+            free: [
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+            ],
+            owners: UnsafeCell::new([0; GRANULES]),
+            base: 0,
+            reserve_start: 0,
+            reserve: AtomicUsize::new(0),
+            spent: AtomicUsize::new(0),
+            #[cfg(feature = "trace-heap")]
+            tracer: SpinLock::new(trace::Tracer::new()),
         }
     }
-    pub fn init(&self) {
+    // takes the ARM memory from the firmware, without the reserved ranges
+    pub fn init(&mut self) {
         use crate::dev::board::bcm2837::*;
         extern "C" {
+            static __start: u64;
             static __end: u64;
         }
-        self.next.set(unsafe { &__end as *const _ as usize});
-        self.end.set((MMIO_BASE - 1) as usize);
+        let start = unsafe { &__start as *const _ as usize };
+        let end = unsafe { &__end as *const _ as usize };
+        // without an answer from the firmware assuming everything up to the peripherals
+        let (base, size) = global![mailbox]
+            .arm_memory()
+            .unwrap_or((0, MMIO_BASE as usize));
+        let top = (base + size).min(MMIO_BASE as usize);
+        let reserved = [
+            // spin table of the parked cores
+            (0, PAGE_SIZE),
+            // boot stack, growing down from the kernel
+            (PAGE_SIZE, start),
+            // kernel image
+            (start, end),
+        ];
+        unsafe {
+            self.add_memory(base, top, &reserved);
+        }
+    }
+    // the memory between start and end without the reserved ranges must be
+    // unused and must stay valid, the emergency reserve and the first heap
+    // of the calling core are taken from it
+    pub(crate) unsafe fn add_memory(&mut self, start: usize, end: usize, reserved: &[(usize, usize)]) {
+        self.base = start & !((1 << GRANULE_SHIFT) - 1);
+        {
+            let mut frames = self.frames.lock();
+            frames.add_region_reserved(start, end, reserved);
+            if let Some(reserve) = frames.alloc_pages(RESERVE_PAGES, PAGE_SIZE) {
+                self.reserve_start = reserve;
+                self.reserve.store(reserve, Ordering::SeqCst);
+            }
+        }
+        self.grow(asm::core_id(), HEAP_PAGES * PAGE_SIZE);
+    }
+    // the free and the total pages of the frame allocator
+    pub fn pages(&self) -> (usize, usize) {
+        let frames = self.frames.lock();
+        (frames.available_pages(), frames.total_pages())
+    }
+    // true while the heap lives on the emergency reserve
+    pub fn is_low(&self) -> bool {
+        self.spent.load(Ordering::SeqCst) != 0
+    }
+    // fails if allocating `size` bytes would likely fail or would eat into the
+    // emergency reserve, for the callers that have a way to back off
+    pub fn check_headroom(&self, size: usize) -> Result<(), AllocError> {
+        // the pages the arenas can still grow by, the smaller blocks are no
+        // use to them
+        let pages = self.frames.lock().available_pages_of(frame::order_of(HEAP_PAGES));
+        // what the emergency arena has free is the rest of the reserve
+        let free: usize = self.free[..CORES].iter().map(|free| free.load(Ordering::Relaxed)).sum();
+        if free + pages * PAGE_SIZE < size {
+            let layout = unsafe { Layout::from_size_align_unchecked(size, 1) };
+            return Err(AllocError { layout });
+        }
+        Ok(())
+    }
+    // all the arenas together
+    pub fn stats(&self) -> HeapStats {
+        let mut stats = self.arenas[0].lock().stats();
+        for arena in self.arenas[1..].iter() {
+            stats.merge(&arena.lock().stats());
+        }
+        stats
+    }
+    pub fn slab_stats(&self) -> [ClassStats; CLASSES] {
+        let mut stats = self.arenas[0].lock().slab_stats();
+        for arena in self.arenas[1..].iter() {
+            let other = arena.lock().slab_stats();
+            for (class, other) in stats.iter_mut().zip(other.iter()) {
+                class.total += other.total;
+                class.used += other.used;
+            }
+        }
+        stats
+    }
+    // the outstanding allocations grouped by call site
+    #[cfg(feature = "trace-heap")]
+    pub fn leaks(&self) -> trace::Summary {
+        self.tracer.lock().summary()
+    }
+    // takes at least `size` bytes from the frame allocator for the arena
+    fn grow(&self, arena: usize, size: usize) -> bool {
+        let pages = ((size + PAGE_SIZE - 1) / PAGE_SIZE).max(HEAP_PAGES);
+        let size = (1 << frame::order_of(pages)) * PAGE_SIZE;
+        let start = {
+            let mut frames = self.frames.lock();
+            let start = match frames.alloc_pages(pages, PAGE_SIZE) {
+                Some(start) => start,
+                None => return false,
+            };
+            self.set_owner(start, size, arena);
+            start
+        };
+        self.with_arena(arena, |arena| unsafe { arena.add_region(start, size) });
+        true
+    }
+    // keeps the free bytes of the arena in step with it
+    fn with_arena<R, F: FnOnce(&mut Arena) -> R>(&self, arena: usize, f: F) -> R {
+        let mut locked = self.arenas[arena].lock();
+        let result = f(&mut locked);
+        self.free[arena].store(locked.free(), Ordering::Relaxed);
+        result
+    }
+    // hands the emergency reserve to its arena, false if it has it already
+    fn use_reserve(&self) -> bool {
+        let used = self.with_arena(EMERGENCY, |emergency| {
+            let reserve = self.reserve.swap(0, Ordering::SeqCst);
+            if reserve == 0 {
+                return false;
+            }
+            self.spent.store(reserve, Ordering::SeqCst);
+            unsafe {
+                emergency.add_region(reserve, RESERVE_PAGES * PAGE_SIZE);
+            }
+            true
+        });
+        if !used {
+            return false;
+        }
+        println!("\n[alloc] out of memory, using the emergency reserve");
+        true
+    }
+    // takes the reserve back from its arena once nothing is allocated from
+    // it, with the lock of the arena held
+    fn refill(&self, emergency: &mut Arena) -> bool {
+        let spent = self.spent.load(Ordering::SeqCst);
+        if spent == 0 || !emergency.clear() {
+            return false;
+        }
+        self.spent.store(0, Ordering::SeqCst);
+        self.reserve.store(spent, Ordering::SeqCst);
+        true
+    }
+    // with the lock of the frames held, so the cores do not write the table
+    // at the same time, the readers only look up the pointers of the arena
+    // which it gets after this
+    fn set_owner(&self, start: usize, size: usize, arena: usize) {
+        let owners = unsafe { &mut *self.owners.get() };
+        let first = (start - self.base) >> GRANULE_SHIFT;
+        let last = (start + size - 1 - self.base) >> GRANULE_SHIFT;
+        for owner in owners[first..=last].iter_mut() {
+            *owner = arena as u8 + 1;
+        }
+    }
+    fn owner(&self, ptr: *mut u8) -> Option<usize> {
+        let reserve = self.reserve_start;
+        if reserve != 0 && (ptr as usize).wrapping_sub(reserve) < RESERVE_PAGES * PAGE_SIZE {
+            return Some(EMERGENCY);
+        }
+        let owners = unsafe { &*self.owners.get() };
+        let granule = (ptr as usize).checked_sub(self.base)? >> GRANULE_SHIFT;
+        match owners.get(granule) {
+            Some(&owner) if owner != 0 => Some(owner as usize - 1),
+            _ => None,
+        }
+    }
+    // the padding is the part of the layout the caller did not ask for, the
+    // red zones of debug-heap, it is left out of the stats
+    unsafe fn allocate(&self, layout: Layout, padding: usize) -> *mut u8 {
+        self.allocate_on(asm::core_id(), layout, padding)
+    }
+    unsafe fn allocate_on(&self, core: usize, layout: Layout, padding: usize) -> *mut u8 {
+        let mut ptr = self.with_arena(core, |arena| arena.allocate(layout, padding));
+        // out of heap, asking for more pages (with room for the alignment)
+        if ptr.is_null() && self.grow(core, layout.size() + layout.align()) {
+            ptr = self.with_arena(core, |arena| arena.allocate(layout, padding));
+        }
+        // what the other cores have free
+        for other in (0..CORES).filter(|&other| other != core) {
+            if !ptr.is_null() {
+                break;
+            }
+            ptr = self.with_arena(other, |arena| arena.allocate(layout, padding));
+        }
+        if ptr.is_null() {
+            self.use_reserve();
+            ptr = self.with_arena(EMERGENCY, |arena| arena.allocate(layout, padding));
+        }
+        #[cfg(feature = "trace-heap")]
+        {
+            if !ptr.is_null() {
+                self.tracer.lock().record(ptr as usize, layout.size() - padding);
+            }
+        }
+        ptr
+    }
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout, padding: usize) {
+        let owner = match self.owner(ptr) {
+            Some(owner) => owner,
+            None => {
+                println!("[alloc] free of foreign pointer {:#010X}, {:?}", ptr as usize, layout);
+                return;
+            }
+        };
+        #[cfg(feature = "trace-heap")]
+        self.tracer.lock().forget(ptr as usize);
+        let refilled = self.with_arena(owner, |arena| {
+            arena.deallocate(ptr, layout, padding);
+            owner == EMERGENCY && self.refill(arena)
+        });
+        if refilled {
+            println!("[alloc] the emergency reserve is back");
+        }
+    }
+    // debug-heap moves every reallocation to check it
+    #[cfg(not(feature = "debug-heap"))]
+    unsafe fn reallocate(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if let Some(owner) = self.owner(ptr) {
+            if self.with_arena(owner, |arena| arena.resize_in_place(ptr, layout, new_size)) {
+                #[cfg(feature = "trace-heap")]
+                self.tracer.lock().resize(ptr as usize, new_size);
+                return ptr;
+            }
+        }
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.allocate(new_layout, 0);
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.deallocate(ptr, layout, 0);
+        }
+        new_ptr
     }
 }
 
+#[cfg(not(feature = "debug-heap"))]
 unsafe impl GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let alignment = layout.align();
-        let start = (self.next.get() + alignment - 1) & !(alignment - 1);
-        let end = start + layout.size();
-        // println!(
-        //     "[i] Allocated Addr {:#010X} Size {:#X}",
-        //     start,
-        //     layout.size()
-        // );
-        if end <= self.end.get() {
-            self.next.set(end);
-            return start as *mut u8;
-        }
-        core::ptr::null_mut()
-    }
-
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
-        // TODO: proper allocator
-        // println!(
-        //     "[i] Deallocated Addr {:#010X} Size {:#X}",
-        //     _ptr as usize,
-        //     _layout.size()
-        // );
+        self.allocate(layout, 0)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.deallocate(ptr, layout, 0);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.reallocate(ptr, layout, new_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the reserve, a MiB for core 0 and 2 MiB to grow by, the rest of the
+    // pages is in blocks too small for the arenas
+    const SIZE: usize = 4 << 20;
+
+    fn allocator(memory: &mut Vec<u8>) -> Box<Allocator> {
+        let start = (memory.as_mut_ptr() as usize + SIZE - 1) & !(SIZE - 1);
+        let mut allocator = Box::new(Allocator::new());
+        unsafe {
+            allocator.add_memory(start, start + SIZE, &[]);
+        }
+        allocator
+    }
+
+    #[test]
+    fn cross_core_free() {
+        let mut memory = vec![0u8; 2 * SIZE];
+        let allocator = allocator(&mut memory);
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        unsafe {
+            // core 1 grows its own arena
+            let ptr = allocator.allocate_on(1, layout, 0);
+            assert_eq!(allocator.owner(ptr), Some(1));
+            let other = allocator.allocate_on(0, layout, 0);
+            assert_eq!(allocator.owner(other), Some(0));
+            // whichever core frees them, they go back to where they came from
+            allocator.deallocate(ptr, layout, 0);
+            allocator.deallocate(other, layout, 0);
+        }
+        for core in 0..2 {
+            let stats = allocator.arenas[core].lock().stats();
+            assert_eq!((stats.allocations, stats.live), (1, 0));
+            assert_eq!(allocator.free[core].load(Ordering::Relaxed), stats.free);
+        }
+        assert_eq!(allocator.owner(core::ptr::null_mut()), None);
+    }
+
+    #[test]
+    fn fallback() {
+        let mut memory = vec![0u8; 2 * SIZE];
+        let allocator = allocator(&mut memory);
+        let layout = Layout::from_size_align(64 * 1024, 8).unwrap();
+        let mut blocks = Vec::new();
+        loop {
+            let ptr = unsafe { allocator.allocate_on(1, layout, 0) };
+            if ptr.is_null() {
+                break;
+            }
+            blocks.push(ptr);
+        }
+        // core 1 took the 2 MiB, then the MiB of core 0 and the reserve
+        let count = |arena| blocks.iter().filter(|&&ptr| allocator.owner(ptr) == Some(arena)).count();
+        assert_eq!((count(1), count(0), count(EMERGENCY)), (32, 16, 1));
+        assert!(allocator.is_low());
+        assert!(allocator.check_headroom(1).is_err());
+        for ptr in blocks.drain(..) {
+            unsafe {
+                allocator.deallocate(ptr, layout, 0);
+            }
+        }
+        // the reserve is held back again, the others have memory to give
+        assert!(!allocator.is_low());
+        assert!(allocator.check_headroom(3 << 20).is_ok());
+        assert!(allocator.check_headroom(4 << 20).is_err());
+        let ptr = unsafe { allocator.allocate_on(2, layout, 0) };
+        assert_eq!(allocator.owner(ptr), Some(0));
+    }
+
+    #[test]
+    fn reserve_granule() {
+        let mut memory = vec![0u8; 2 * SIZE];
+        let allocator = allocator(&mut memory);
+        // the pages next to the reserve given to core 2, like a grow smaller
+        // than a granule would
+        let start = allocator.frames.lock().alloc_pages(RESERVE_PAGES, PAGE_SIZE).unwrap();
+        assert_eq!(start >> GRANULE_SHIFT, allocator.reserve_start >> GRANULE_SHIFT);
+        allocator.set_owner(start, RESERVE_PAGES * PAGE_SIZE, 2);
+        unsafe {
+            allocator.arenas[2].lock().add_region(start, RESERVE_PAGES * PAGE_SIZE);
+        }
+        assert_eq!(allocator.owner(allocator.reserve_start as *mut u8), Some(EMERGENCY));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        unsafe {
+            let ptr = allocator.allocate_on(2, layout, 0);
+            assert_eq!(allocator.owner(ptr), Some(2));
+            // back to its own arena, not to the one of the reserve
+            allocator.deallocate(ptr, layout, 0);
+        }
+        let stats = allocator.arenas[2].lock().stats();
+        assert_eq!((stats.allocations, stats.live), (1, 0));
+        let stats = allocator.arenas[EMERGENCY].lock().stats();
+        assert_eq!((stats.allocations, stats.live), (0, 0));
     }
 }
@@ -0,0 +1,117 @@
+use core::alloc::Layout;
+use core::ptr;
+use super::heap::Heap;
+
+// the object sizes served by the slabs, anything bigger goes to the heap
+const CLASS_SIZES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+pub const CLASSES: usize = CLASS_SIZES.len();
+
+// the slabs are carved from the heap in this unit, aligned to its own size so
+// every object is aligned to its class size
+const SLAB_SIZE: usize = 4096;
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct SizeClass {
+    free: *mut FreeObject,
+    // objects carved from the heap
+    total: usize,
+    // objects handed out
+    used: usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct ClassStats {
+    pub size: usize,
+    pub total: usize,
+    pub used: usize,
+}
+
+impl core::fmt::Display for ClassStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:>5} B {:>6}/{:<6}", self.size, self.used, self.total)
+    }
+}
+
+pub struct Slab {
+    classes: [SizeClass; CLASSES],
+}
+
+impl Slab {
+    pub const fn new() -> Slab {
+        Slab {
+            classes: [
+                SizeClass { free: ptr::null_mut(), total: 0, used: 0 },
+                SizeClass { free: ptr::null_mut(), total: 0, used: 0 },
+                SizeClass { free: ptr::null_mut(), total: 0, used: 0 },
+                SizeClass { free: ptr::null_mut(), total: 0, used: 0 },
+                SizeClass { free: ptr::null_mut(), total: 0, used: 0 },
+                SizeClass { free: ptr::null_mut(), total: 0, used: 0 },
+                SizeClass { free: ptr::null_mut(), total: 0, used: 0 },
+            ],
+        }
+    }
+    // the class serving the layout, None if it is for the heap
+    #[inline]
+    pub fn class_of(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+        CLASS_SIZES.iter().position(|&class| size <= class)
+    }
+    pub fn allocate(&mut self, class: usize, heap: &mut Heap) -> *mut u8 {
+        if self.classes[class].free.is_null() && !self.refill(class, heap) {
+            return ptr::null_mut();
+        }
+        let c = &mut self.classes[class];
+        let object = c.free;
+        c.free = unsafe { (*object).next };
+        c.used += 1;
+        object as *mut u8
+    }
+    pub unsafe fn deallocate(&mut self, class: usize, ptr: *mut u8) {
+        let c = &mut self.classes[class];
+        let object = ptr as *mut FreeObject;
+        (*object).next = c.free;
+        c.free = object;
+        c.used -= 1;
+    }
+    // bytes of the carved objects that are not handed out
+    pub fn free_space(&self) -> usize {
+        self.classes
+            .iter()
+            .zip(CLASS_SIZES.iter())
+            .map(|(c, size)| (c.total - c.used) * size)
+            .sum()
+    }
+    pub fn stats(&self) -> [ClassStats; CLASSES] {
+        let mut stats = [ClassStats { size: 0, total: 0, used: 0 }; CLASSES];
+        for (i, c) in self.classes.iter().enumerate() {
+            stats[i] = ClassStats {
+                size: CLASS_SIZES[i],
+                total: c.total,
+                used: c.used,
+            };
+        }
+        stats
+    }
+    // carves a new slab from the heap and threads its objects to the free list
+    fn refill(&mut self, class: usize, heap: &mut Heap) -> bool {
+        let layout = unsafe { Layout::from_size_align_unchecked(SLAB_SIZE, SLAB_SIZE) };
+        let slab = heap.allocate(layout) as usize;
+        if slab == 0 {
+            return false;
+        }
+        let size = CLASS_SIZES[class];
+        let c = &mut self.classes[class];
+        for i in (0..SLAB_SIZE / size).rev() {
+            let object = (slab + i * size) as *mut FreeObject;
+            unsafe {
+                (*object).next = c.free;
+            }
+            c.free = object;
+        }
+        c.total += SLAB_SIZE / size;
+        true
+    }
+}
@@ -0,0 +1,170 @@
+// allocation tracer: every live allocation is recorded with its size, the time
+// and the return addresses of its call chain, the addresses can be resolved
+// with `make nm` or addr2line
+
+use crate::asm;
+use super::PAGE_SIZE;
+
+const RECORDS: usize = 1024;
+// return addresses kept per allocation
+const DEPTH: usize = 6;
+// return addresses inside the allocator itself
+const SKIP: usize = 2;
+// call sites shown by the report
+const SITES: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Record {
+    ptr: usize,
+    size: usize,
+    time: u64,
+    site: [usize; DEPTH],
+}
+
+const EMPTY: Record = Record {
+    ptr: 0,
+    size: 0,
+    time: 0,
+    site: [0; DEPTH],
+};
+
+#[derive(Clone, Copy)]
+struct Site {
+    site: [usize; DEPTH],
+    count: usize,
+    bytes: usize,
+    oldest: u64,
+}
+
+// walks the frame records (x29, x30 pairs) up the boot stack
+#[inline(always)]
+fn backtrace() -> [usize; DEPTH] {
+    extern "C" {
+        static __start: u64;
+    }
+    let top = unsafe { &__start as *const _ as usize };
+    let mut site = [0; DEPTH];
+    let mut fp = asm::frame_pointer();
+    let mut depth = 0;
+    while fp >= PAGE_SIZE && fp < top && fp % 16 == 0 && depth < SKIP + DEPTH {
+        let (next, lr) = unsafe { (*(fp as *const usize), *((fp + 8) as *const usize)) };
+        if depth >= SKIP {
+            site[depth - SKIP] = lr;
+        }
+        depth += 1;
+        // the stack grows down, so the callers' records are above
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+    site
+}
+
+pub struct Tracer {
+    records: [Record; RECORDS],
+    live: usize,
+    // allocations that did not fit into the table
+    dropped: usize,
+}
+
+impl Tracer {
+    pub const fn new() -> Tracer {
+        Tracer {
+            records: [EMPTY; RECORDS],
+            live: 0,
+            dropped: 0,
+        }
+    }
+    #[inline(never)]
+    pub fn record(&mut self, ptr: usize, size: usize) {
+        let site = backtrace();
+        match self.records.iter_mut().find(|r| r.ptr == 0) {
+            Some(r) => {
+                *r = Record {
+                    ptr,
+                    size,
+                    time: asm::counter(),
+                    site,
+                };
+                self.live += 1;
+            },
+            None => self.dropped += 1,
+        }
+    }
+    #[cfg(not(feature = "debug-heap"))]
+    pub fn resize(&mut self, ptr: usize, size: usize) {
+        if let Some(r) = self.records.iter_mut().find(|r| r.ptr == ptr) {
+            r.size = size;
+        }
+    }
+    pub fn forget(&mut self, ptr: usize) {
+        if let Some(r) = self.records.iter_mut().find(|r| r.ptr == ptr) {
+            *r = EMPTY;
+            self.live -= 1;
+        }
+    }
+    // the outstanding allocations grouped by call site, collected on the stack
+    // so that printing it does not change the records
+    pub fn summary(&self) -> Summary {
+        let mut summary = Summary {
+            sites: [Site { site: [0; DEPTH], count: 0, bytes: 0, oldest: 0 }; SITES],
+            used: 0,
+            other: 0,
+            live: self.live,
+            dropped: self.dropped,
+            now: asm::counter(),
+        };
+        for r in self.records.iter().filter(|r| r.ptr != 0) {
+            let used = summary.used;
+            match summary.sites[..used].iter_mut().find(|s| s.site == r.site) {
+                Some(s) => {
+                    s.count += 1;
+                    s.bytes += r.size;
+                    s.oldest = s.oldest.min(r.time);
+                },
+                None if used < SITES => {
+                    summary.sites[used] = Site { site: r.site, count: 1, bytes: r.size, oldest: r.time };
+                    summary.used += 1;
+                },
+                None => summary.other += 1,
+            }
+        }
+        summary.sites[..summary.used].sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes));
+        summary
+    }
+}
+
+pub struct Summary {
+    sites: [Site; SITES],
+    used: usize,
+    // allocations from the call sites that did not fit
+    other: usize,
+    live: usize,
+    dropped: usize,
+    now: u64,
+}
+
+impl core::fmt::Display for Summary {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let frequency = asm::counter_frequency();
+        writeln!(f, "{} live allocations, {} not traced", self.live, self.dropped)?;
+        for s in self.sites[..self.used].iter() {
+            write!(
+                f,
+                "{:>6} x {:>8} B, oldest {} ms ago\n   at",
+                s.count,
+                s.bytes,
+                (self.now - s.oldest) * 1000 / frequency
+            )?;
+            for lr in s.site.iter().take_while(|&&lr| lr != 0) {
+                write!(f, " {:#010X}", lr)?;
+            }
+            writeln!(f)?;
+        }
+        if self.other > 0 {
+            writeln!(f, "{:>6} more from other call sites", self.other)?;
+        }
+        Ok(())
+    }
+}
@@ -1,15 +1,33 @@
-pub struct Interrupt {}
+use crate::dev::board::bcm2837::*;
+use crate::sys::reactor::deferred::wake;
+
+pub struct Interrupt {
+    local: *const LOCAL,
+}
 
 impl Interrupt {
     pub const fn new() -> Self {
-        Interrupt {}
+        Interrupt {
+            local: LOCAL_BASE as *const LOCAL,
+        }
     }
     #[inline]
     pub fn interrupt_enable(&self) {
-        global![mini_uart].interrupt_enable();
+        global![mini_uart].lock().interrupt_enable();
+        global![timer].interrupt_enable();
     }
     #[inline]
     pub fn process(&self) {
-        global![mini_uart].try_read_char();
+        let source = unsafe { &(*self.local).CORE_IRQ_SOURCE[0] };
+        if source.is_set(CORE_IRQ_SOURCE::CNTPNSIRQ) {
+            // the loop looks at the deadlines and arms the timer again
+            global![timer].disarm();
+            global![deferred].defer(wake, 0);
+        }
+        if source.is_set(CORE_IRQ_SOURCE::GPU) {
+            let mut mini_uart = global![mini_uart].lock();
+            mini_uart.try_read_char();
+            mini_uart.try_wake_writer();
+        }
     }
 }
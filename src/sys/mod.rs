@@ -1,3 +1,5 @@
 pub mod alloc;
 pub mod exception;
-pub mod reactor;
\ No newline at end of file
+pub mod reactor;
+pub mod shell;
+pub mod sync;
\ No newline at end of file
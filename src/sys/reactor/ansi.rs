@@ -0,0 +1,447 @@
+// the escape sequences of VT100 like terminals, the types are Display so they
+// go into print!, write! and the strings of Loop::put_string alike
+//
+//     println!("{}{}", Cursor::To(1, 1), Clear::Screen);
+//     println!("{}", Style::new().fg(Color::Green).bold().paint("ok"));
+
+use alloc::prelude::v1::*;
+use alloc::rc::Rc;
+use core::cell::{Cell, RefCell};
+use core::fmt::{self, Write};
+use crate::sys::alloc::AllocError;
+use super::io::OpFuture;
+use super::OpHandle;
+
+const ESC: char = '\x1b';
+
+// how long the terminal has to report its size
+const QUERY_TIMEOUT: u64 = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Color {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    BrightBlack,
+    BrightRed,
+    BrightGreen,
+    BrightYellow,
+    BrightBlue,
+    BrightMagenta,
+    BrightCyan,
+    BrightWhite,
+    // of the terminal
+    Default,
+}
+
+impl Color {
+    // the code of the foreground, the background is 10 more
+    fn code(self) -> u8 {
+        match self {
+            Color::Default => 39,
+            color if (color as u8) < 8 => 30 + color as u8,
+            color => 90 + color as u8 - 8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Attr {
+    Bold,
+    Dim,
+    Italic,
+    Underline,
+    Blink,
+    Reverse,
+    Hidden,
+    Strike,
+}
+
+impl Attr {
+    fn code(self) -> u8 {
+        match self {
+            Attr::Bold => 1,
+            Attr::Dim => 2,
+            Attr::Italic => 3,
+            Attr::Underline => 4,
+            Attr::Blink => 5,
+            Attr::Reverse => 7,
+            Attr::Hidden => 8,
+            Attr::Strike => 9,
+        }
+    }
+}
+
+// the SGR sequence of the colors and attributes, the empty style resets
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Style {
+    fg: Option<Color>,
+    bg: Option<Color>,
+    // bits of the Attr codes
+    attrs: u16,
+}
+
+impl Style {
+    pub fn new() -> Style {
+        Style::default()
+    }
+    pub fn fg(mut self, color: Color) -> Style {
+        self.fg = Some(color);
+        self
+    }
+    pub fn bg(mut self, color: Color) -> Style {
+        self.bg = Some(color);
+        self
+    }
+    pub fn attr(mut self, attr: Attr) -> Style {
+        self.attrs |= 1 << attr.code();
+        self
+    }
+    pub fn bold(self) -> Style {
+        self.attr(Attr::Bold)
+    }
+    pub fn underline(self) -> Style {
+        self.attr(Attr::Underline)
+    }
+    pub fn reverse(self) -> Style {
+        self.attr(Attr::Reverse)
+    }
+    // the value in the style, the style is reset after it
+    pub fn paint<T: fmt::Display>(self, value: T) -> Painted<T> {
+        Painted { style: self, value }
+    }
+}
+
+impl fmt::Display for Style {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}[0", ESC)?;
+        for code in 1..10 {
+            if self.attrs & (1 << code) != 0 {
+                write!(f, ";{}", code)?;
+            }
+        }
+        if let Some(fg) = self.fg {
+            write!(f, ";{}", fg.code())?;
+        }
+        if let Some(bg) = self.bg {
+            write!(f, ";{}", bg.code() + 10)?;
+        }
+        write!(f, "m")
+    }
+}
+
+pub struct Painted<T> {
+    style: Style,
+    value: T,
+}
+
+impl<T: fmt::Display> fmt::Display for Painted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}{}", self.style, self.value, Style::new())
+    }
+}
+
+// the moves by 0 write nothing, the terminals move by 1 for them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cursor {
+    Up(usize),
+    Down(usize),
+    Right(usize),
+    Left(usize),
+    // row and column, from 1
+    To(usize, usize),
+    Save,
+    Restore,
+    Hide,
+    Show,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Cursor::Up(0) | Cursor::Down(0) | Cursor::Right(0) | Cursor::Left(0) => Ok(()),
+            Cursor::Up(n) => write!(f, "{}[{}A", ESC, n),
+            Cursor::Down(n) => write!(f, "{}[{}B", ESC, n),
+            Cursor::Right(n) => write!(f, "{}[{}C", ESC, n),
+            Cursor::Left(n) => write!(f, "{}[{}D", ESC, n),
+            Cursor::To(row, column) => write!(f, "{}[{};{}H", ESC, row, column),
+            Cursor::Save => write!(f, "{}7", ESC),
+            Cursor::Restore => write!(f, "{}8", ESC),
+            Cursor::Hide => write!(f, "{}[?25l", ESC),
+            Cursor::Show => write!(f, "{}[?25h", ESC),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clear {
+    Screen,
+    // from the cursor on
+    ScreenAfter,
+    ScreenBefore,
+    Line,
+    LineAfter,
+    LineBefore,
+}
+
+impl fmt::Display for Clear {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (n, what) = match self {
+            Clear::ScreenAfter => (0, 'J'),
+            Clear::ScreenBefore => (1, 'J'),
+            Clear::Screen => (2, 'J'),
+            Clear::LineAfter => (0, 'K'),
+            Clear::LineBefore => (1, 'K'),
+            Clear::Line => (2, 'K'),
+        };
+        if n == 0 {
+            write!(f, "{}[{}", ESC, what)
+        } else {
+            write!(f, "{}[{}{}", ESC, n, what)
+        }
+    }
+}
+
+// the rows that scroll, from 1 and inclusive, the rows around them stay put,
+// None scrolls the whole screen again
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScrollRegion(pub Option<(usize, usize)>);
+
+impl fmt::Display for ScrollRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some((top, bottom)) => write!(f, "{}[{};{}r", ESC, top, bottom),
+            None => write!(f, "{}[r", ESC),
+        }
+    }
+}
+
+// a bar drawn over the current line, printed again as the work goes on
+//
+//     [##########          ]  50%
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+    // of the bar, without the brackets
+    pub width: usize,
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let done = self.done.min(self.total);
+        let (filled, percent) = match self.total {
+            0 => (self.width, 100),
+            total => (done * self.width / total, done * 100 / total),
+        };
+        write!(f, "\r[")?;
+        for i in 0..self.width {
+            write!(f, "{}", if i < filled { '#' } else { ' ' })?;
+        }
+        write!(f, "] {:>3}%{}", percent, Clear::LineAfter)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Size {
+    pub rows: usize,
+    pub columns: usize,
+}
+
+// reads the cursor position report, ESC [ row ; column R
+#[derive(Default)]
+struct Report {
+    state: u8,
+    row: usize,
+    column: usize,
+}
+
+impl Report {
+    fn feed(&mut self, c: char) -> Option<Size> {
+        let digit = c.to_digit(10).map(|d| d as usize);
+        self.state = match (self.state, c, digit) {
+            (_, ESC, _) => {
+                self.row = 0;
+                self.column = 0;
+                1
+            },
+            (1, '[', _) => 2,
+            (2, _, Some(d)) => {
+                self.row = self.row.saturating_mul(10).saturating_add(d);
+                2
+            },
+            (2, ';', _) => 3,
+            (3, _, Some(d)) => {
+                self.column = self.column.saturating_mul(10).saturating_add(d);
+                3
+            },
+            (3, 'R', _) => {
+                self.state = 0;
+                return Some(Size {
+                    rows: self.row,
+                    columns: self.column,
+                });
+            },
+            _ => 0,
+        };
+        None
+    }
+}
+
+// cancels the op of the other half of a query when it is dropped, the loop
+// drops the ops after its borrow
+struct Linked(Rc<Cell<Option<OpHandle>>>);
+
+impl Linked {
+    fn cancel(&self) {
+        if let Some(handle) = self.0.take() {
+            global![default_loop].cancel(handle);
+        }
+    }
+}
+
+impl Drop for Linked {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+// the cursor goes to the far corner and the terminal is asked where it
+// ended up, the callback gets None if there is no answer in time, the answer
+// is read by a tap so it reaches the readers as well, the line editor drops it
+//
+// the handle is the one of the tap, the timeout goes with it
+pub fn query_size(callback: Box<dyn Fn(Option<Size>)>) -> Result<OpHandle, AllocError> {
+    let default_loop = global![default_loop];
+    let callback: Rc<dyn Fn(Option<Size>)> = Rc::from(callback);
+    let report = RefCell::new(Report::default());
+    let timeout = Rc::new(Cell::new(None));
+    let tap = Rc::new(Cell::new(None));
+    let handle = {
+        let (callback, timeout, tap) = (callback.clone(), Linked(timeout.clone()), tap.clone());
+        default_loop.try_tap(move |c| {
+            let size = report.borrow_mut().feed(c);
+            if size.is_some() {
+                timeout.cancel();
+                if let Some(handle) = tap.get() {
+                    global![default_loop].cancel(handle);
+                }
+                callback(size);
+            }
+        })?
+    };
+    tap.set(Some(handle));
+    // without it the tap is cancelled
+    let tap = Linked(tap);
+    timeout.set(Some(default_loop.try_set_timeout(QUERY_TIMEOUT, move || {
+        tap.cancel();
+        callback(None);
+    })?));
+    let mut query = String::new();
+    write!(query, "{}{}{}[6n{}", Cursor::Save, Cursor::To(999, 999), ESC, Cursor::Restore).unwrap();
+    if let Err(e) = default_loop.try_put_string(&query, || {}) {
+        // the timeout goes with the tap
+        default_loop.cancel(handle);
+        return Err(e);
+    }
+    Ok(handle)
+}
+
+pub fn size() -> OpFuture<Option<Size>> {
+    OpFuture::new(|completer| query_size(Box::new(move |size| completer.complete(size))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::task::{Context, RawWaker, RawWakerVTable, Waker};
+    use super::super::source::Event;
+    use super::super::stats::OpKind;
+    use super::super::tests::Fake;
+    use crate::sys::sync::SpinLock;
+
+    fn dummy_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(core::ptr::null(), &VTABLE)
+        }
+        fn nothing(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, nothing, nothing, nothing);
+        unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) }
+    }
+
+    #[test]
+    fn sequences() {
+        let style = Style::new().fg(Color::BrightRed).bg(Color::Blue).bold().underline();
+        assert_eq!(format!("{}", style), "\x1b[0;1;4;91;44m");
+        assert_eq!(format!("{}", Style::new().fg(Color::Default).paint("x")), "\x1b[0;39mx\x1b[0m");
+        assert_eq!(format!("{}{}", Cursor::To(3, 7), Cursor::Left(0)), "\x1b[3;7H");
+        assert_eq!(format!("{}{}", Clear::Screen, Clear::LineAfter), "\x1b[2J\x1b[K");
+        assert_eq!(format!("{}{}", ScrollRegion(Some((2, 24))), ScrollRegion(None)), "\x1b[2;24r\x1b[r");
+    }
+
+    #[test]
+    fn progress() {
+        let progress = Progress { done: 5, total: 10, width: 4 };
+        assert_eq!(format!("{}", progress), "\r[##  ]  50%\x1b[K");
+        let progress = Progress { done: 0, total: 0, width: 2 };
+        assert_eq!(format!("{}", progress), "\r[##] 100%\x1b[K");
+    }
+
+    #[test]
+    fn report() {
+        let mut report = Report::default();
+        let mut size = None;
+        for c in "x\x1b[\x1b[24;80R".chars() {
+            size = report.feed(c).or(size);
+        }
+        assert_eq!(size, Some(Size { rows: 24, columns: 80 }));
+    }
+
+    // the ops of the query on a global![default_loop] with a fake console
+    fn pending() -> (u64, u64) {
+        let stats = global![default_loop].stats();
+        (stats.kind(OpKind::Tap).pending, stats.kind(OpKind::Timer).pending)
+    }
+
+    #[test]
+    fn query() {
+        let _turn = super::super::tests::turn();
+        let default_loop = global![default_loop];
+        default_loop.reset();
+        let output = Rc::new(RefCell::new(String::new()));
+        let console = Fake(Rc::new(RefCell::new(VecDeque::new())), output.clone());
+        default_loop.register_source(Box::leak(Box::new(SpinLock::new(console))));
+        let sizes = Rc::new(RefCell::new(Vec::new()));
+        let s = sizes.clone();
+        let handle = query_size(Box::new(move |size| s.borrow_mut().push(size))).unwrap();
+        assert_eq!(pending(), (1, 1));
+        // the timeout goes with the tap
+        assert!(default_loop.cancel(handle));
+        assert_eq!(pending(), (0, 0));
+        default_loop.run();
+        // the counter stands still on the host, the timeout is due right away
+        let s = sizes.clone();
+        query_size(Box::new(move |size| s.borrow_mut().push(size))).unwrap();
+        default_loop.run();
+        assert_eq!(*sizes.borrow(), [None]);
+        assert_eq!(pending(), (0, 0));
+        assert!(output.borrow().contains("\x1b[6n"));
+        // dropping the future cancels both
+        drop(size());
+        let mut future = size();
+        let waker = dummy_waker();
+        let mut context = Context::from_waker(&waker);
+        assert!(Pin::new(&mut future).poll(&mut context).is_pending());
+        assert_eq!(pending(), (1, 1));
+        drop(future);
+        assert_eq!(pending(), (0, 0));
+    }
+}
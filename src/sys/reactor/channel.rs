@@ -0,0 +1,364 @@
+// bounded channels between the components of the loop, the items are kept in
+// the order they were sent, a full channel holds the senders back and an
+// empty one the receiver, waiting is a Loop operation so it can be cancelled
+//
+// spsc has one sender, the senders of mpsc are cloned, both have one
+// receiver, the channel is closed once either side is gone
+
+use alloc::prelude::v1::*;
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use core::ops::Deref;
+use crate::sys::alloc::{AllocError, try_box};
+use super::{OpHandle, OP_HEADROOM};
+
+#[derive(Debug, PartialEq)]
+pub enum SendError<T> {
+    // the item is given back
+    Full(T),
+    Closed(T),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RecvError {
+    Empty,
+    // no sender is left and every item was received
+    Closed,
+}
+
+pub(super) struct Channel<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receiver: bool,
+}
+
+impl<T> Channel<T> {
+    fn new(capacity: usize) -> Channel<T> {
+        assert!(capacity > 0, "a channel needs room for an item");
+        Channel {
+            items: VecDeque::with_capacity(capacity),
+            capacity,
+            senders: 1,
+            receiver: true,
+        }
+    }
+    fn send(&mut self, item: T) -> Result<(), SendError<T>> {
+        if !self.receiver {
+            return Err(SendError::Closed(item));
+        }
+        if self.items.len() == self.capacity {
+            return Err(SendError::Full(item));
+        }
+        self.items.push_back(item);
+        Ok(())
+    }
+    fn recv(&mut self) -> Result<T, RecvError> {
+        match self.items.pop_front() {
+            Some(item) => Ok(item),
+            None if self.senders == 0 => Err(RecvError::Closed),
+            None => Err(RecvError::Empty),
+        }
+    }
+}
+
+// an op waiting on a channel, ready is checked every pass while the loop is
+// borrowed, so it only looks at the channel, complete calls back after
+pub(super) trait Waiter {
+    fn ready(&mut self) -> bool;
+    fn complete(self: Box<Self>);
+}
+
+struct SendWaiter<T> {
+    channel: Rc<RefCell<Channel<T>>>,
+    // the item until it is sent
+    item: Option<T>,
+    result: Option<Result<(), T>>,
+    callback: Box<dyn Fn(Result<(), T>)>,
+}
+
+impl<T> Waiter for SendWaiter<T> {
+    fn ready(&mut self) -> bool {
+        let item = self.item.take().unwrap();
+        let result = self.channel.borrow_mut().send(item);
+        match result {
+            Ok(()) => self.result = Some(Ok(())),
+            Err(SendError::Closed(item)) => self.result = Some(Err(item)),
+            Err(SendError::Full(item)) => {
+                self.item = Some(item);
+                return false;
+            }
+        }
+        global![default_loop].notify();
+        true
+    }
+    fn complete(self: Box<Self>) {
+        let this = *self;
+        (this.callback)(this.result.unwrap());
+    }
+}
+
+struct RecvWaiter<T> {
+    channel: Rc<RefCell<Channel<T>>>,
+    result: Option<Option<T>>,
+    callback: Box<dyn Fn(Option<T>)>,
+}
+
+impl<T> Waiter for RecvWaiter<T> {
+    fn ready(&mut self) -> bool {
+        let result = self.channel.borrow_mut().recv();
+        match result {
+            Ok(item) => self.result = Some(Some(item)),
+            Err(RecvError::Closed) => self.result = Some(None),
+            Err(RecvError::Empty) => return false,
+        }
+        // there is room for the senders
+        global![default_loop].notify();
+        true
+    }
+    fn complete(self: Box<Self>) {
+        let this = *self;
+        (this.callback)(this.result.unwrap());
+    }
+}
+
+// the futures hold the channel, not the ends
+pub(super) fn wait_send<T, F>(channel: Rc<RefCell<Channel<T>>>, item: T, callback: F) -> Result<OpHandle, AllocError>
+where
+    T: 'static,
+    F: Fn(Result<(), T>) + 'static,
+{
+    global![allocator].check_headroom(OP_HEADROOM)?;
+    let callback = try_box(callback)?;
+    let waiter = try_box(SendWaiter {
+        channel,
+        item: Some(item),
+        result: None,
+        callback,
+    })?;
+    Ok(global![default_loop].wait(waiter))
+}
+
+pub(super) fn wait_recv<T, F>(channel: Rc<RefCell<Channel<T>>>, callback: F) -> Result<OpHandle, AllocError>
+where
+    T: 'static,
+    F: Fn(Option<T>) + 'static,
+{
+    global![allocator].check_headroom(OP_HEADROOM)?;
+    let callback = try_box(callback)?;
+    let waiter = try_box(RecvWaiter {
+        channel,
+        result: None,
+        callback,
+    })?;
+    Ok(global![default_loop].wait(waiter))
+}
+
+pub struct Sender<T> {
+    pub(super) channel: Rc<RefCell<Channel<T>>>,
+}
+
+impl<T: 'static> Sender<T> {
+    // does not wait, the blocked senders can be overtaken
+    pub fn send_now(&self, item: T) -> Result<(), SendError<T>> {
+        self.channel.borrow_mut().send(item)?;
+        global![default_loop].notify();
+        Ok(())
+    }
+    // waits for room, the callback gets the item back if the receiver is
+    // gone, fails when memory is low
+    pub fn try_send<F>(&self, item: T, callback: F) -> Result<OpHandle, AllocError>
+    where
+        F: Fn(Result<(), T>) + 'static,
+    {
+        wait_send(self.channel.clone(), item, callback)
+    }
+    pub fn is_closed(&self) -> bool {
+        !self.channel.borrow().receiver
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut channel = self.channel.borrow_mut();
+        channel.senders -= 1;
+        // the receiver gets None
+        if channel.senders == 0 {
+            global![default_loop].notify();
+        }
+    }
+}
+
+// a sender of mpsc
+pub struct SharedSender<T>(Sender<T>);
+
+impl<T> Clone for SharedSender<T> {
+    fn clone(&self) -> Self {
+        self.0.channel.borrow_mut().senders += 1;
+        SharedSender(Sender {
+            channel: self.0.channel.clone(),
+        })
+    }
+}
+
+impl<T> Deref for SharedSender<T> {
+    type Target = Sender<T>;
+    fn deref(&self) -> &Sender<T> {
+        &self.0
+    }
+}
+
+pub struct Receiver<T> {
+    pub(super) channel: Rc<RefCell<Channel<T>>>,
+}
+
+impl<T: 'static> Receiver<T> {
+    // does not wait
+    pub fn recv_now(&self) -> Result<T, RecvError> {
+        let item = self.channel.borrow_mut().recv()?;
+        global![default_loop].notify();
+        Ok(item)
+    }
+    // waits for an item, the callback gets None once the senders are gone,
+    // fails when memory is low
+    pub fn try_recv<F: Fn(Option<T>) + 'static>(&self, callback: F) -> Result<OpHandle, AllocError> {
+        wait_recv(self.channel.clone(), callback)
+    }
+    pub fn len(&self) -> usize {
+        self.channel.borrow().items.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut channel = self.channel.borrow_mut();
+        channel.receiver = false;
+        channel.items.clear();
+        // the blocked senders get their items back
+        global![default_loop].notify();
+    }
+}
+
+// the buffer is allocated here, sending does not allocate
+pub fn spsc<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let channel = Rc::new(RefCell::new(Channel::new(capacity)));
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+pub fn mpsc<T>(capacity: usize) -> (SharedSender<T>, Receiver<T>) {
+    let (sender, receiver) = spsc(capacity);
+    (SharedSender(sender), receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sys::sync::SpinLockGuard;
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    // the waits go through global![default_loop], it starts empty
+    fn setup() -> (SpinLockGuard<'static, ()>, Log) {
+        let turn = super::super::tests::turn();
+        global![default_loop].reset();
+        (turn, Rc::new(RefCell::new(Vec::new())))
+    }
+
+    fn logger<T: core::fmt::Debug>(log: &Log, name: &'static str) -> impl Fn(T) {
+        let log = log.clone();
+        move |result| log.borrow_mut().push(format!("{} {:?}", name, result))
+    }
+
+    fn take(log: &Log) -> Vec<String> {
+        log.borrow_mut().drain(..).collect()
+    }
+
+    #[test]
+    fn bounded() {
+        let mut channel = Channel::new(2);
+        assert_eq!(channel.send(1), Ok(()));
+        assert_eq!(channel.send(2), Ok(()));
+        assert_eq!(channel.send(3), Err(SendError::Full(3)));
+        assert_eq!(channel.recv(), Ok(1));
+        assert_eq!(channel.send(3), Ok(()));
+        assert_eq!(channel.recv(), Ok(2));
+        assert_eq!(channel.recv(), Ok(3));
+        assert_eq!(channel.recv(), Err(RecvError::Empty));
+    }
+
+    #[test]
+    fn closed() {
+        let mut channel = Channel::new(2);
+        channel.send(1).unwrap();
+        channel.senders = 0;
+        // the items sent before are still received
+        assert_eq!(channel.recv(), Ok(1));
+        assert_eq!(channel.recv(), Err(RecvError::Closed));
+        channel.receiver = false;
+        assert_eq!(channel.send(2), Err(SendError::Closed(2)));
+    }
+
+    #[test]
+    fn backpressure() {
+        let (_turn, log) = setup();
+        let (sender, receiver) = spsc(1);
+        sender.send_now(1).unwrap();
+        assert_eq!(sender.send_now(2), Err(SendError::Full(2)));
+        sender.try_send(2, logger(&log, "sent")).unwrap();
+        global![default_loop].run();
+        assert_eq!(take(&log), Vec::<String>::new());
+        // the receiver makes room, the blocked sender goes next
+        receiver.try_recv(logger(&log, "received")).unwrap();
+        global![default_loop].run();
+        assert_eq!(take(&log), ["received Some(1)", "sent Ok(())"]);
+        assert_eq!(receiver.recv_now(), Ok(2));
+        assert_eq!(receiver.recv_now(), Err(RecvError::Empty));
+    }
+
+    #[test]
+    fn closed_on_drop() {
+        let (_turn, log) = setup();
+        let (sender, receiver) = spsc::<u32>(1);
+        receiver.try_recv(logger(&log, "received")).unwrap();
+        global![default_loop].run();
+        drop(sender);
+        global![default_loop].run();
+        assert_eq!(take(&log), ["received None"]);
+        // the blocked sender gets its item back
+        let (sender, receiver) = spsc(1);
+        sender.send_now(1).unwrap();
+        sender.try_send(2, logger(&log, "sent")).unwrap();
+        global![default_loop].run();
+        drop(receiver);
+        global![default_loop].run();
+        assert_eq!(take(&log), ["sent Err(2)"]);
+        assert!(sender.is_closed());
+    }
+
+    #[test]
+    fn shared_senders() {
+        let (_turn, log) = setup();
+        let (sender, receiver) = mpsc(2);
+        let other = sender.clone();
+        receiver.try_recv(logger(&log, "received")).unwrap();
+        drop(sender);
+        global![default_loop].run();
+        // one of them is left
+        assert_eq!(take(&log), Vec::<String>::new());
+        other.send_now(5).unwrap();
+        global![default_loop].run();
+        receiver.try_recv(logger(&log, "received")).unwrap();
+        drop(other);
+        global![default_loop].run();
+        assert_eq!(take(&log), ["received Some(5)", "received None"]);
+    }
+}
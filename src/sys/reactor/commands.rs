@@ -0,0 +1,18 @@
+// the shell commands of the loop
+
+use alloc::prelude::v1::*;
+use core::fmt::Write;
+use crate::sys::shell::{Builtin, ShellError};
+
+pub fn register() {
+    global![shell].register(Box::new(Builtin {
+        name: "loopstat",
+        help: "loopstat, the operations of the loop and where its time goes",
+        run: loopstat,
+    }));
+}
+
+fn loopstat(_: &[String], s: &mut String) -> Result<(), ShellError> {
+    writeln!(s, "{}", global![default_loop].stats())?;
+    Ok(())
+}
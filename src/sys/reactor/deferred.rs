@@ -0,0 +1,163 @@
+// work the interrupt handlers hand to the loop, the items are a function and
+// its argument so queueing one does not allocate, the loop runs them in the
+// order they came
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::asm;
+
+const CAPACITY: usize = 64;
+
+pub type Work = fn(u64);
+
+// an item that only makes the loop run a pass
+pub fn wake(_: u64) {
+    global![default_loop].wake();
+}
+
+#[derive(Clone, Copy)]
+struct Item {
+    work: Option<Work>,
+    arg: u64,
+}
+
+const EMPTY: Item = Item {
+    work: None,
+    arg: 0,
+};
+
+// a ring with one consumer, the producers are serialized by masking the
+// interrupts, only loads and stores are used on the counters
+pub struct Deferred {
+    items: UnsafeCell<[Item; CAPACITY]>,
+    // items queued, written by the producers
+    head: AtomicUsize,
+    // items taken, written by the loop
+    tail: AtomicUsize,
+    // items that did not fit
+    overflows: AtomicUsize,
+    // the most items that were waiting at once
+    high_water: AtomicUsize,
+}
+
+unsafe impl Sync for Deferred {}
+
+impl Deferred {
+    pub const fn new() -> Deferred {
+        Deferred {
+            items: UnsafeCell::new([EMPTY; CAPACITY]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            overflows: AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
+        }
+    }
+    // safe to call from the interrupt handlers, false if the queue is full
+    pub fn defer(&self, work: Work, arg: u64) -> bool {
+        let daif = asm::irq_save();
+        let head = self.head.load(Ordering::Relaxed);
+        let waiting = head.wrapping_sub(self.tail.load(Ordering::Acquire));
+        let queued = waiting < CAPACITY;
+        if queued {
+            unsafe {
+                (*self.items.get())[head % CAPACITY] = Item {
+                    work: Some(work),
+                    arg,
+                };
+            }
+            self.head.store(head.wrapping_add(1), Ordering::Release);
+            if waiting + 1 > self.high_water.load(Ordering::Relaxed) {
+                self.high_water.store(waiting + 1, Ordering::Relaxed);
+            }
+        } else {
+            self.overflows.store(self.overflows.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        }
+        asm::irq_restore(daif);
+        queued
+    }
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Relaxed)
+    }
+    // runs the items queued so far, the ones they queue wait for the next
+    // call, returns how many ran
+    pub fn run(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        let mut ran = 0;
+        while tail != head {
+            let item = unsafe { (*self.items.get())[tail % CAPACITY] };
+            tail = tail.wrapping_add(1);
+            // the slot can be reused before the work runs
+            self.tail.store(tail, Ordering::Release);
+            if let Some(work) = item.work {
+                work(item.arg);
+            }
+            ran += 1;
+        }
+        ran
+    }
+    pub fn overflows(&self) -> usize {
+        self.overflows.load(Ordering::Relaxed)
+    }
+    pub fn high_water(&self) -> usize {
+        self.high_water.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static RAN: RefCell<Vec<u64>> = RefCell::new(Vec::new());
+        static DEFERRED: Deferred = Deferred::new();
+    }
+
+    fn record(arg: u64) {
+        RAN.with(|ran| ran.borrow_mut().push(arg));
+    }
+
+    fn requeue(arg: u64) {
+        DEFERRED.with(|deferred| assert!(deferred.defer(record, arg + 1)));
+    }
+
+    #[test]
+    fn in_order() {
+        let deferred = Deferred::new();
+        for i in 0..10 {
+            assert!(deferred.defer(record, i));
+        }
+        assert_eq!(deferred.run(), 10);
+        assert!(deferred.is_empty());
+        RAN.with(|ran| assert_eq!(*ran.borrow(), (0..10).collect::<Vec<_>>()));
+    }
+
+    #[test]
+    fn overflow() {
+        let deferred = Deferred::new();
+        for i in 0..CAPACITY as u64 + 3 {
+            deferred.defer(record, i);
+        }
+        assert_eq!(deferred.overflows(), 3);
+        assert_eq!(deferred.high_water(), CAPACITY);
+        assert_eq!(deferred.run(), CAPACITY);
+        // there is room again after the wrap
+        for i in 0..CAPACITY as u64 {
+            assert!(deferred.defer(record, i));
+        }
+        assert_eq!(deferred.run(), CAPACITY);
+        assert_eq!(deferred.overflows(), 3);
+    }
+
+    #[test]
+    fn queued_while_running() {
+        DEFERRED.with(|deferred| {
+            deferred.defer(requeue, 0);
+            assert_eq!(deferred.run(), 1);
+            assert!(!deferred.is_empty());
+            assert_eq!(deferred.run(), 1);
+        });
+        RAN.with(|ran| assert_eq!(*ran.borrow(), vec![1]));
+    }
+}
@@ -0,0 +1,144 @@
+// the line discipline of a terminal, between the device and the consumers of
+// the loop, like termios
+//
+// in the cooked mode a CR read is a NL, a NL written is CR NL, Ctrl-C is an
+// interrupt and the lines read are echoed as the echo mode says, the single
+// characters only if asked for, the raw mode passes everything as it is and
+// echoes nothing
+
+use core::fmt;
+use super::source::Event;
+
+const CTRL_C: char = '\x03';
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Cooked,
+    Raw,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Echo {
+    On,
+    Off,
+    // only the end of the line is echoed, for the passwords
+    Password,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Discipline {
+    pub mode: Mode,
+    pub echo: Echo,
+    // CR to NL on input, NL to CR NL on output
+    pub map_crlf: bool,
+    // Ctrl-C is an Event::Interrupt
+    pub interrupt: bool,
+    // the characters taken by read_char are echoed like the lines
+    pub echo_chars: bool,
+}
+
+impl Default for Discipline {
+    fn default() -> Discipline {
+        Discipline {
+            mode: Mode::Cooked,
+            echo: Echo::On,
+            map_crlf: true,
+            interrupt: true,
+            echo_chars: false,
+        }
+    }
+}
+
+impl Discipline {
+    pub fn raw() -> Discipline {
+        Discipline {
+            mode: Mode::Raw,
+            echo: Echo::Off,
+            map_crlf: false,
+            interrupt: false,
+            echo_chars: false,
+        }
+    }
+    fn cooked(&self) -> bool {
+        self.mode == Mode::Cooked
+    }
+    // the event of a character of the device
+    pub fn input(&self, c: char) -> Event {
+        match c {
+            '\r' if self.cooked() && self.map_crlf => Event::Char('\n'),
+            CTRL_C if self.cooked() && self.interrupt => Event::Interrupt,
+            c => Event::Char(c),
+        }
+    }
+    // the characters written to the device for c
+    pub fn output<F: FnMut(char)>(&self, c: char, mut write: F) {
+        if c == '\n' && self.cooked() && self.map_crlf {
+            write('\r');
+        }
+        write(c);
+    }
+    // how much of the echo of a reader is shown, the echo ends the line if
+    // the line is done
+    pub fn echo<'a>(&self, echo: &'a str, done: bool) -> &'a str {
+        if !self.cooked() {
+            return "";
+        }
+        match self.echo {
+            Echo::On => echo,
+            Echo::Password if done => "\n",
+            _ => "",
+        }
+    }
+}
+
+// writes through the discipline, for the formatted output
+pub struct Output<'a, W: fmt::Write> {
+    pub discipline: Discipline,
+    pub device: &'a mut W,
+}
+
+impl<'a, W: fmt::Write> fmt::Write for Output<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let device = &mut self.device;
+        let mut result = Ok(());
+        for c in s.chars() {
+            self.discipline.output(c, |c| {
+                if result.is_ok() {
+                    result = device.write_char(c);
+                }
+            });
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Write;
+
+    #[test]
+    fn cooked() {
+        let discipline = Discipline::default();
+        assert_eq!(discipline.input('\r'), Event::Char('\n'));
+        assert_eq!(discipline.input(CTRL_C), Event::Interrupt);
+        let mut s = String::new();
+        write!(Output { discipline, device: &mut s }, "a\nb").unwrap();
+        assert_eq!(s, "a\r\nb");
+        assert_eq!(discipline.echo("ab", false), "ab");
+        let password = Discipline { echo: Echo::Password, ..discipline };
+        assert_eq!(password.echo("ab", false), "");
+        assert_eq!(password.echo("b\n", true), "\n");
+    }
+
+    #[test]
+    fn raw() {
+        let discipline = Discipline::raw();
+        assert_eq!(discipline.input('\r'), Event::Char('\r'));
+        assert_eq!(discipline.input(CTRL_C), Event::Char(CTRL_C));
+        let mut s = String::new();
+        write!(Output { discipline, device: &mut s }, "a\nb").unwrap();
+        assert_eq!(s, "a\nb");
+        assert_eq!(discipline.echo("ab", true), "");
+    }
+}
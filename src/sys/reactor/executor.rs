@@ -0,0 +1,182 @@
+use alloc::prelude::v1::*;
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use core::cell::{Cell, RefCell};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, RawWaker, RawWakerVTable, Waker};
+
+struct Task {
+    // None once the future is finished
+    future: RefCell<Option<Pin<Box<dyn Future<Output = ()>>>>>,
+    woken: Cell<bool>,
+}
+
+// the wakers point to the task, they are only used on the core running the
+// executor, so the reference count does not need atomics
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone_waker, wake, wake_by_ref, drop_waker);
+
+unsafe fn clone_waker(task: *const ()) -> RawWaker {
+    let task = Rc::from_raw(task as *const Task);
+    let clone = task.clone();
+    core::mem::forget(task);
+    RawWaker::new(Rc::into_raw(clone) as *const (), &VTABLE)
+}
+
+unsafe fn wake(task: *const ()) {
+    let task = Rc::from_raw(task as *const Task);
+    task.woken.set(true);
+}
+
+unsafe fn wake_by_ref(task: *const ()) {
+    (*(task as *const Task)).woken.set(true);
+}
+
+unsafe fn drop_waker(task: *const ()) {
+    drop(Rc::from_raw(task as *const Task));
+}
+
+fn waker(task: &Rc<Task>) -> Waker {
+    let raw = RawWaker::new(Rc::into_raw(task.clone()) as *const (), &VTABLE);
+    unsafe { Waker::from_raw(raw) }
+}
+
+// the tasks are only touched by the core running the executor, between the
+// polls, a task can spawn while it is polled
+pub struct Executor {
+    tasks: RefCell<Option<Vec<Rc<Task>>>>,
+    // the tasks spawned since the last poll, run takes them over after it
+    spawned: RefCell<Option<VecDeque<Rc<Task>>>>,
+}
+
+unsafe impl Sync for Executor {}
+
+impl Executor {
+    pub const fn new() -> Self {
+        Executor {
+            tasks: RefCell::new(None),
+            spawned: RefCell::new(None)
+        }
+    }
+    pub fn init(&self) {
+        *self.tasks.borrow_mut() = Some(Vec::new());
+        *self.spawned.borrow_mut() = Some(VecDeque::new());
+    }
+    pub fn spawn<F: Future<Output = ()> + 'static>(&self, future: F) {
+        self.spawned.borrow_mut().as_mut().unwrap().push_back(Rc::new(Task {
+            future: RefCell::new(Some(Box::pin(future))),
+            // polled on the next run
+            woken: Cell::new(true),
+        }));
+    }
+    pub fn is_dirty(&self) -> bool {
+        !self.spawned.borrow().as_ref().unwrap().is_empty()
+            || self.tasks.borrow().as_ref().unwrap().iter().any(|task| task.woken.get())
+    }
+    // moves the spawned tasks to the others
+    fn take_spawned(&self) {
+        let mut spawned = self.spawned.borrow_mut();
+        self.tasks.borrow_mut().as_mut().unwrap().extend(spawned.as_mut().unwrap().drain(..));
+    }
+    // polls the woken tasks once and drops the finished ones
+    pub fn run(&self) {
+        // the bottom halves of the interrupts first, they wake the tasks
+        global![deferred].run();
+        self.take_spawned();
+        let mut i = 0;
+        // tasks spawned while polling are picked up by this loop as well
+        loop {
+            let task = match self.tasks.borrow().as_ref().unwrap().get(i) {
+                Some(task) => task.clone(),
+                None => break,
+            };
+            if task.woken.replace(false) {
+                let waker = waker(&task);
+                let mut context = Context::from_waker(&waker);
+                let mut future = task.future.borrow_mut();
+                let finished = match future.as_mut() {
+                    Some(f) => f.as_mut().poll(&mut context).is_ready(),
+                    None => true,
+                };
+                if finished {
+                    *future = None;
+                }
+                self.take_spawned();
+            }
+            i += 1;
+        }
+        self.tasks.borrow_mut().as_mut().unwrap().retain(|task| task.future.borrow().is_some());
+    }
+    // sleeps until an interrupt if no task is woken, the loop keeps the
+    // account of the sleeps
+    pub fn idle(&self) {
+        if !self.is_dirty() {
+            global![default_loop].idle();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::task::Poll;
+
+    // pending until the flag is set, the waker is kept for the test
+    struct Flag(Rc<Cell<bool>>, Rc<RefCell<Option<Waker>>>);
+
+    impl Future for Flag {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            if self.0.get() {
+                return Poll::Ready(());
+            }
+            *self.1.borrow_mut() = Some(context.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn wake_and_finish() {
+        let executor = Executor::new();
+        executor.init();
+        let flag = Rc::new(Cell::new(false));
+        let waker = Rc::new(RefCell::new(None));
+        let done = Rc::new(Cell::new(false));
+        let (f, w, d) = (flag.clone(), waker.clone(), done.clone());
+        executor.spawn(async move {
+            Flag(f, w).await;
+            d.set(true);
+        });
+        assert!(executor.is_dirty());
+        executor.run();
+        // waiting for the wake
+        assert!(!executor.is_dirty());
+        assert!(!done.get());
+        flag.set(true);
+        waker.borrow_mut().take().unwrap().wake();
+        assert!(executor.is_dirty());
+        executor.run();
+        assert!(done.get());
+        assert_eq!(executor.tasks.borrow().as_ref().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn spawn_while_polled() {
+        let executor: &'static Executor = Box::leak(Box::new(Executor::new()));
+        executor.init();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let l = log.clone();
+        executor.spawn(async move {
+            l.borrow_mut().push("outer");
+            let inner = l.clone();
+            executor.spawn(async move {
+                inner.borrow_mut().push("inner");
+            });
+        });
+        // the spawned one is polled in the same run
+        executor.run();
+        assert_eq!(*log.borrow(), ["outer", "inner"]);
+        assert!(!executor.is_dirty());
+        assert_eq!(executor.tasks.borrow().as_ref().unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,169 @@
+// futures over the Loop operations, for the tasks of the executor
+
+use alloc::prelude::v1::*;
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use core::mem;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use crate::sys::alloc::{AllocError, try_box, try_string};
+use super::OpHandle;
+use super::channel::{self, Receiver, Sender};
+use super::line_editor::LineOptions;
+
+struct Shared<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+}
+
+// completes the future from the callback of the operation
+pub struct Completer<T>(Rc<RefCell<Shared<T>>>);
+
+impl<T> Completer<T> {
+    pub fn complete(&self, value: T) {
+        let mut shared = self.0.borrow_mut();
+        shared.value = Some(value);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Clone for Completer<T> {
+    fn clone(&self) -> Self {
+        Completer(self.0.clone())
+    }
+}
+
+type Submit<T> = dyn FnOnce(Completer<T>) -> Result<OpHandle, AllocError>;
+
+// a Loop operation, submitted on the first poll and cancelled when the future
+// is dropped before it completes
+pub struct OpFuture<T> {
+    // None if there was no room for it
+    shared: Option<Rc<RefCell<Shared<T>>>>,
+    // an error if it or the state could not be allocated, the first poll
+    // returns it
+    submit: Option<Result<Box<Submit<T>>, AllocError>>,
+    handle: Option<OpHandle>,
+}
+
+impl<T> OpFuture<T> {
+    pub fn new<F>(submit: F) -> Self
+    where
+        F: FnOnce(Completer<T>) -> Result<OpHandle, AllocError> + 'static,
+    {
+        // Rc::new can not fail, it is only called with room for the state
+        // and the counts
+        let size = mem::size_of::<RefCell<Shared<T>>>() + 2 * mem::size_of::<usize>();
+        if let Err(e) = global![allocator].check_headroom(size) {
+            return OpFuture {
+                shared: None,
+                submit: Some(Err(e)),
+                handle: None,
+            };
+        }
+        OpFuture {
+            shared: Some(Rc::new(RefCell::new(Shared {
+                value: None,
+                waker: None,
+            }))),
+            submit: Some(try_box(submit).map(|submit| submit as Box<Submit<T>>)),
+            handle: None,
+        }
+    }
+}
+
+impl<T> Future for OpFuture<T> {
+    type Output = Result<T, AllocError>;
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Some(submit) = this.submit.take() {
+            let submit = submit?;
+            let shared = this.shared.clone().unwrap();
+            this.handle = Some(submit(Completer(shared))?);
+        }
+        // the error was returned already
+        let mut shared = match this.shared.as_ref() {
+            Some(shared) => shared.borrow_mut(),
+            None => return Poll::Pending,
+        };
+        match shared.value.take() {
+            Some(value) => Poll::Ready(Ok(value)),
+            None => {
+                shared.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for OpFuture<T> {
+    fn drop(&mut self) {
+        // does nothing if the operation is done already
+        if let Some(handle) = self.handle {
+            global![default_loop].cancel(handle);
+        }
+    }
+}
+
+pub fn read_line() -> OpFuture<String> {
+    OpFuture::new(|completer| {
+        global![default_loop].try_read_line(move |line| completer.complete(line))
+    })
+}
+
+pub fn read_line_with(options: LineOptions) -> OpFuture<String> {
+    OpFuture::new(|completer| {
+        global![default_loop].try_read_line_with(options, move |line| completer.complete(line))
+    })
+}
+
+pub fn read_char() -> OpFuture<char> {
+    OpFuture::new(|completer| {
+        global![default_loop].try_read_char(move |c| completer.complete(c))
+    })
+}
+
+pub fn write_str(s: &str) -> OpFuture<()> {
+    let s = try_string(s);
+    OpFuture::new(move |completer| {
+        global![default_loop].try_put_string(&s?, move || completer.complete(()))
+    })
+}
+
+// None if nothing was read in the given milliseconds
+pub fn read_line_timeout(ms: u64) -> OpFuture<Option<String>> {
+    OpFuture::new(move |completer| {
+        global![default_loop].try_read_line_timeout(ms, move |line| completer.complete(line))
+    })
+}
+
+pub fn read_char_timeout(ms: u64) -> OpFuture<Option<char>> {
+    OpFuture::new(move |completer| {
+        global![default_loop].try_read_char_timeout(ms, move |c| completer.complete(c))
+    })
+}
+
+pub fn sleep(ms: u64) -> OpFuture<()> {
+    OpFuture::new(move |completer| {
+        global![default_loop].try_set_timeout(ms, move || completer.complete(()))
+    })
+}
+
+// None once the senders are gone
+pub fn recv<T: 'static>(receiver: &Receiver<T>) -> OpFuture<Option<T>> {
+    let channel = receiver.channel.clone();
+    OpFuture::new(move |completer| {
+        channel::wait_recv(channel, move |item| completer.complete(item))
+    })
+}
+
+// waits for room, Err gives the item back if the receiver is gone
+pub fn send<T: 'static>(sender: &Sender<T>, item: T) -> OpFuture<Result<(), T>> {
+    let channel = sender.channel.clone();
+    OpFuture::new(move |completer| {
+        channel::wait_send(channel, item, move |result| completer.complete(result))
+    })
+}
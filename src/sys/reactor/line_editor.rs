@@ -0,0 +1,523 @@
+// the line editor behind Loop::read_line, it takes the characters of a
+// terminal one at a time and writes the echo and the redraws to the given
+// string, the escape sequences of the keys are parsed here
+//
+// the keys: the arrows, home, end and delete, backspace, Ctrl-A and Ctrl-E to
+// the start and the end, Ctrl-B and Ctrl-F for a character, Ctrl-K kills to
+// the end, Ctrl-U to the start and Ctrl-W the word before the cursor, Up and
+// Down go through the history, Tab completes the word before the cursor and
+// a second Tab lists the candidates
+//
+// the line is expected to fit the width of the terminal
+
+use alloc::prelude::v1::*;
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use core::fmt::Write;
+use super::ansi::{Clear, Cursor};
+
+// the lines kept by default
+pub const HISTORY_DEPTH: usize = 16;
+
+const ESC: char = '\x1b';
+
+// the lines entered on a source, the oldest first
+pub struct History {
+    lines: VecDeque<String>,
+    depth: usize,
+}
+
+impl History {
+    pub fn new(depth: usize) -> History {
+        History {
+            lines: VecDeque::new(),
+            depth,
+        }
+    }
+    // 0 turns the history off
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        while self.lines.len() > depth {
+            self.lines.pop_front();
+        }
+    }
+    // the empty lines and the repeats of the last one are not kept
+    fn push(&mut self, line: &str) {
+        if self.depth == 0 || line.trim().is_empty() || self.lines.back().map_or(false, |last| last == line) {
+            return;
+        }
+        if self.lines.len() == self.depth {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line.to_string());
+    }
+    // 0 is the newest
+    fn get(&self, back: usize) -> Option<&String> {
+        self.lines.len().checked_sub(back + 1).and_then(|i| self.lines.get(i))
+    }
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+// the words that can go where the cursor is, the line is given up to the
+// cursor with the start of the word in it, the ones that do not start with
+// the word are left out
+pub trait Completions {
+    fn candidates(&self, line: &str, start: usize) -> Vec<String>;
+}
+
+impl<F: Fn(&str, usize) -> Vec<String>> Completions for F {
+    fn candidates(&self, line: &str, start: usize) -> Vec<String> {
+        self(line, start)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct LineOptions {
+    // written before the line is read and again after the candidates are
+    // listed
+    pub prompt: String,
+    pub completions: Option<Rc<dyn Completions>>,
+}
+
+enum Escape {
+    None,
+    // after ESC
+    Start,
+    // after ESC [, with the first parameter and whether it is still read
+    Csi(u32, bool),
+    // after ESC O
+    Ss3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Key {
+    Insert(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    KillEnd,
+    KillStart,
+    KillWord,
+    Complete,
+}
+
+pub struct LineEditor {
+    buffer: Vec<char>,
+    cursor: usize,
+    escape: Escape,
+    // the history entry shown, 0 is the newest
+    recall: Option<usize>,
+    // the line typed before going through the history
+    typed: Vec<char>,
+    options: LineOptions,
+    // the last key was a Tab that did not complete anything
+    listing: bool,
+}
+
+impl LineEditor {
+    // the buffer is reused, it can be allocated up front
+    pub fn new(buffer: Vec<char>) -> LineEditor {
+        LineEditor::with_options(buffer, LineOptions::default())
+    }
+    pub fn with_options(mut buffer: Vec<char>, options: LineOptions) -> LineEditor {
+        buffer.clear();
+        LineEditor {
+            buffer,
+            cursor: 0,
+            escape: Escape::None,
+            recall: None,
+            typed: Vec::new(),
+            options,
+            listing: false,
+        }
+    }
+    // the line when it is entered, it is added to the history then
+    pub fn feed(&mut self, history: &mut History, c: char, out: &mut String) -> Option<String> {
+        let key = self.key(c)?;
+        let listing = self.listing;
+        self.listing = false;
+        match key {
+            Key::Insert(c) => {
+                self.buffer.insert(self.cursor, c);
+                self.cursor += 1;
+                if self.cursor == self.buffer.len() {
+                    out.push(c);
+                } else {
+                    self.redraw(self.cursor - 1, self.cursor - 1, out);
+                }
+            },
+            Key::Enter => {
+                move_right(self.buffer.len() - self.cursor, out);
+                out.push('\n');
+                let line: String = self.buffer.drain(..).collect();
+                self.cursor = 0;
+                self.recall = None;
+                history.push(&line);
+                return Some(line);
+            },
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.buffer.remove(self.cursor);
+                self.redraw(self.cursor + 1, self.cursor, out);
+            },
+            Key::Delete if self.cursor < self.buffer.len() => {
+                self.buffer.remove(self.cursor);
+                self.redraw(self.cursor, self.cursor, out);
+            },
+            Key::Left if self.cursor > 0 => {
+                self.cursor -= 1;
+                move_left(1, out);
+            },
+            Key::Right if self.cursor < self.buffer.len() => {
+                self.cursor += 1;
+                move_right(1, out);
+            },
+            Key::Home => {
+                move_left(self.cursor, out);
+                self.cursor = 0;
+            },
+            Key::End => {
+                move_right(self.buffer.len() - self.cursor, out);
+                self.cursor = self.buffer.len();
+            },
+            Key::KillEnd => {
+                self.buffer.truncate(self.cursor);
+                self.redraw(self.cursor, self.cursor, out);
+            },
+            Key::KillStart => {
+                let at = self.cursor;
+                self.buffer.drain(..self.cursor);
+                self.cursor = 0;
+                self.redraw(at, 0, out);
+            },
+            Key::KillWord => {
+                let at = self.cursor;
+                let mut start = at;
+                while start > 0 && self.buffer[start - 1] == ' ' {
+                    start -= 1;
+                }
+                while start > 0 && self.buffer[start - 1] != ' ' {
+                    start -= 1;
+                }
+                self.buffer.drain(start..at);
+                self.cursor = start;
+                self.redraw(at, start, out);
+            },
+            Key::Up => {
+                let back = self.recall.map_or(0, |back| back + 1);
+                if let Some(line) = history.get(back) {
+                    if self.recall.is_none() {
+                        self.typed = self.buffer.clone();
+                    }
+                    self.recall = Some(back);
+                    let line = line.chars().collect();
+                    self.replace(line, out);
+                }
+            },
+            Key::Complete => self.complete(listing, out),
+            Key::Down => match self.recall {
+                Some(0) => {
+                    self.recall = None;
+                    let typed = core::mem::replace(&mut self.typed, Vec::new());
+                    self.replace(typed, out);
+                },
+                // the history may have shrunk since
+                Some(back) => match history.get(back - 1) {
+                    Some(line) => {
+                        self.recall = Some(back - 1);
+                        let line = line.chars().collect();
+                        self.replace(line, out);
+                    },
+                    None => {
+                        self.recall = None;
+                        let typed = core::mem::replace(&mut self.typed, Vec::new());
+                        self.replace(typed, out);
+                    },
+                },
+                None => {},
+            },
+            _ => {},
+        }
+        None
+    }
+    // the history went down to `len` lines, the oldest one left stands in for
+    // the entry shown if that is gone
+    pub fn clamp_recall(&mut self, len: usize) {
+        if self.recall.map_or(false, |back| back >= len) {
+            self.recall = len.checked_sub(1);
+        }
+    }
+    // drops the line and starts over after the prompt
+    pub fn interrupt(&mut self, out: &mut String) {
+        move_right(self.buffer.len() - self.cursor, out);
+        out.push_str("^C\n");
+        out.push_str(&self.options.prompt);
+        self.buffer.clear();
+        self.cursor = 0;
+        self.recall = None;
+        self.listing = false;
+    }
+    // the line so far
+    pub fn line(&self) -> String {
+        self.buffer.iter().collect()
+    }
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+    // the key of the character, None in the middle of an escape sequence
+    fn key(&mut self, c: char) -> Option<Key> {
+        let (escape, key) = match (&self.escape, c) {
+            (Escape::None, ESC) => (Escape::Start, None),
+            (Escape::None, '\n') => (Escape::None, Some(Key::Enter)),
+            (Escape::None, '\x7f') | (Escape::None, '\x08') => (Escape::None, Some(Key::Backspace)),
+            (Escape::None, '\x01') => (Escape::None, Some(Key::Home)),
+            (Escape::None, '\x02') => (Escape::None, Some(Key::Left)),
+            (Escape::None, '\x04') => (Escape::None, Some(Key::Delete)),
+            (Escape::None, '\x05') => (Escape::None, Some(Key::End)),
+            (Escape::None, '\x06') => (Escape::None, Some(Key::Right)),
+            (Escape::None, '\x0b') => (Escape::None, Some(Key::KillEnd)),
+            (Escape::None, '\x0e') => (Escape::None, Some(Key::Down)),
+            (Escape::None, '\x10') => (Escape::None, Some(Key::Up)),
+            (Escape::None, '\x15') => (Escape::None, Some(Key::KillStart)),
+            (Escape::None, '\x17') => (Escape::None, Some(Key::KillWord)),
+            (Escape::None, '\t') => (Escape::None, Some(Key::Complete)),
+            // the other control characters do not end up in the line
+            (Escape::None, c) if c.is_control() => (Escape::None, None),
+            (Escape::None, c) => (Escape::None, Some(Key::Insert(c))),
+            (Escape::Start, '[') => (Escape::Csi(0, true), None),
+            (Escape::Start, 'O') => (Escape::Ss3, None),
+            (Escape::Start, _) => (Escape::None, None),
+            (Escape::Csi(n, true), '0'..='9') => {
+                let n = n.saturating_mul(10).saturating_add(c.to_digit(10).unwrap());
+                (Escape::Csi(n, true), None)
+            },
+            // only the first parameter matters for the keys
+            (Escape::Csi(n, _), '0'..='9') | (Escape::Csi(n, _), ';') => (Escape::Csi(*n, false), None),
+            (Escape::Csi(n, _), '~') => (Escape::None, match n {
+                1 | 7 => Some(Key::Home),
+                3 => Some(Key::Delete),
+                4 | 8 => Some(Key::End),
+                _ => None,
+            }),
+            (Escape::Csi(..), c) | (Escape::Ss3, c) => (Escape::None, match c {
+                'A' => Some(Key::Up),
+                'B' => Some(Key::Down),
+                'C' => Some(Key::Right),
+                'D' => Some(Key::Left),
+                'H' => Some(Key::Home),
+                'F' => Some(Key::End),
+                _ => None,
+            }),
+        };
+        self.escape = escape;
+        key
+    }
+    // one candidate is taken with a space after it, of more their common start
+    // is taken, if there is none the next Tab lists them
+    fn complete(&mut self, listing: bool, out: &mut String) {
+        let completions = match &self.options.completions {
+            Some(completions) => completions.clone(),
+            None => return,
+        };
+        let mut start = self.cursor;
+        while start > 0 && self.buffer[start - 1] != ' ' {
+            start -= 1;
+        }
+        let line: String = self.buffer[..self.cursor].iter().collect();
+        // the start in bytes, for the provider
+        let word_start = line.char_indices().nth(start).map_or(line.len(), |(i, _)| i);
+        let word = &line[word_start..];
+        let mut candidates = completions.candidates(&line, word_start);
+        candidates.retain(|candidate| candidate.starts_with(word));
+        candidates.sort();
+        candidates.dedup();
+        let typed = word.chars().count();
+        let mut insert: Vec<char> = match candidates.len() {
+            0 => return,
+            1 => candidates[0].chars().skip(typed).chain(Some(' ')).collect(),
+            _ => common_start(&candidates).chars().skip(typed).collect(),
+        };
+        if !insert.is_empty() {
+            let at = self.cursor;
+            let len = insert.len();
+            self.buffer.splice(at..at, insert.drain(..));
+            self.cursor += len;
+            self.redraw(at, at, out);
+        } else if listing {
+            move_right(self.buffer.len() - self.cursor, out);
+            out.push('\n');
+            out.push_str(&candidates.join("  "));
+            out.push('\n');
+            out.push_str(&self.options.prompt);
+            self.redraw(0, 0, out);
+        } else {
+            self.listing = true;
+        }
+    }
+    // the terminal cursor is at the given position, the line is drawn again
+    // from the given position on and the cursor is put back where it belongs
+    fn redraw(&self, at: usize, from: usize, out: &mut String) {
+        move_left(at - from, out);
+        out.extend(self.buffer[from..].iter());
+        // what is left of a longer line
+        write!(out, "{}", Clear::LineAfter).unwrap();
+        move_left(self.buffer.len() - self.cursor, out);
+    }
+    // shows another line, the cursor goes to its end
+    fn replace(&mut self, line: Vec<char>, out: &mut String) {
+        let at = self.cursor;
+        self.buffer = line;
+        self.cursor = self.buffer.len();
+        self.redraw(at, 0, out);
+    }
+}
+
+fn common_start(words: &[String]) -> &str {
+    let first = &words[0];
+    let mut end = first.len();
+    for word in words[1..].iter() {
+        end = first
+            .char_indices()
+            .zip(word.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(end.min(word.len()), |((i, _), _)| i.min(end));
+    }
+    &first[..end]
+}
+
+fn move_left(n: usize, out: &mut String) {
+    write!(out, "{}", Cursor::Left(n)).unwrap();
+}
+
+fn move_right(n: usize, out: &mut String) {
+    write!(out, "{}", Cursor::Right(n)).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the line and the cursor after the keys
+    fn edit(history: &mut History, keys: &str) -> (Option<String>, String, usize) {
+        let mut editor = LineEditor::new(Vec::new());
+        let mut out = String::new();
+        let mut line = None;
+        for c in keys.chars() {
+            if let Some(l) = editor.feed(history, c, &mut out) {
+                line = Some(l);
+            }
+        }
+        (line, editor.line(), editor.cursor())
+    }
+
+    #[test]
+    fn cursor_and_kills() {
+        let mut history = History::new(HISTORY_DEPTH);
+        assert_eq!(edit(&mut history, "helo\x1b[Dl"), (None, "hello".to_string(), 4));
+        assert_eq!(edit(&mut history, "abc\x01x\x05y"), (None, "xabcy".to_string(), 5));
+        assert_eq!(edit(&mut history, "abc\x1b[D\x1b[D\x0b"), (None, "a".to_string(), 1));
+        assert_eq!(edit(&mut history, "abc\x1b[D\x15"), (None, "c".to_string(), 0));
+        assert_eq!(edit(&mut history, "mem info  \x17"), (None, "mem ".to_string(), 4));
+        assert_eq!(edit(&mut history, "ab\x7f\x7f\x7fc"), (None, "c".to_string(), 1));
+        assert_eq!(edit(&mut history, "abc\x1bOH\x1b[3~"), (None, "bc".to_string(), 0));
+        // the unknown sequences and control characters are dropped
+        assert_eq!(edit(&mut history, "a\x1b[5~\x07\tb"), (None, "ab".to_string(), 2));
+        assert_eq!(edit(&mut history, "ab\x1b[1;5D\x1b[3;5~"), (None, "a".to_string(), 1));
+    }
+
+    #[test]
+    fn history() {
+        let mut history = History::new(2);
+        for line in ["one\n", "two\n", "two\n", "\n", "three\n"].iter() {
+            edit(&mut history, line);
+        }
+        assert_eq!(history.len(), 2);
+        assert_eq!(edit(&mut history, "\x1b[A\x1b[A\n").0, Some("two".to_string()));
+        assert_eq!(edit(&mut history, "x\x1b[A\x1b[A\x1b[A\x1b[B\x1b[B"), (None, "x".to_string(), 1));
+        history.set_depth(0);
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn shrunk_history() {
+        let mut history = History::new(3);
+        for line in ["one\n", "two\n", "three\n"].iter() {
+            edit(&mut history, line);
+        }
+        let mut editor = LineEditor::new(Vec::new());
+        let mut out = String::new();
+        for c in "x\x1b[A\x1b[A\x1b[A".chars() {
+            editor.feed(&mut history, c, &mut out);
+        }
+        assert_eq!(editor.line(), "one");
+        history.set_depth(1);
+        // Down goes back to what was typed, the entries before it are gone
+        for c in "\x1b[B".chars() {
+            editor.feed(&mut history, c, &mut out);
+        }
+        assert_eq!(editor.line(), "x");
+    }
+
+    #[test]
+    fn complete() {
+        let commands = |line: &str, start: usize| -> Vec<String> {
+            if start > 0 {
+                return (0..20).map(|pin| pin.to_string()).filter(|_| line.starts_with("gpio ")).collect();
+            }
+            ["meminfo", "memtest", "leaks"].iter().map(|c| c.to_string()).collect()
+        };
+        let mut editor = LineEditor::with_options(Vec::new(), LineOptions {
+            prompt: "> ".to_string(),
+            completions: Some(Rc::new(commands)),
+        });
+        let mut history = History::new(0);
+        let mut out = String::new();
+        let mut feed = |editor: &mut LineEditor, keys: &str, out: &mut String| {
+            out.clear();
+            for c in keys.chars() {
+                editor.feed(&mut history, c, out);
+            }
+        };
+        feed(&mut editor, "l\t", &mut out);
+        assert_eq!(editor.line(), "leaks ");
+        feed(&mut editor, "\x15m\t", &mut out);
+        assert_eq!(editor.line(), "mem");
+        // the first Tab finds nothing to add, the second lists
+        feed(&mut editor, "\t", &mut out);
+        assert_eq!(out, "");
+        feed(&mut editor, "\t", &mut out);
+        assert_eq!(out, "\nmeminfo  memtest\n> mem\x1b[K");
+        feed(&mut editor, "i\t", &mut out);
+        assert_eq!((editor.line(), editor.cursor()), ("meminfo ".to_string(), 8));
+        feed(&mut editor, "\x15gpio 1\t\t", &mut out);
+        assert_eq!(out.matches('\n').count(), 2);
+        assert!(out.contains("1  10  11"));
+        feed(&mut editor, "7\t", &mut out);
+        assert_eq!(editor.line(), "gpio 17 ");
+    }
+
+    #[test]
+    fn redraw() {
+        let mut history = History::new(HISTORY_DEPTH);
+        let mut editor = LineEditor::new(Vec::new());
+        let mut out = String::new();
+        for c in "ac\x1b[Db".chars() {
+            editor.feed(&mut history, c, &mut out);
+        }
+        assert_eq!(out, "ac\x1b[1Dbc\x1b[K\x1b[1D");
+        out.clear();
+        editor.feed(&mut history, '\n', &mut out);
+        assert_eq!(out, "\x1b[1C\n");
+    }
+}
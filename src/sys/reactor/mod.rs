@@ -1,162 +1,1424 @@
-use alloc::prelude::*;
-use alloc::collections::BTreeMap;
-use core::cell::RefCell;
+pub mod ansi;
+pub mod channel;
+pub mod commands;
+pub mod deferred;
+pub mod discipline;
+pub mod executor;
+pub mod io;
+pub mod line_editor;
+pub mod source;
+pub mod stats;
 
+use alloc::prelude::v1::*;
+use alloc::collections::VecDeque;
+use core::cell::{Cell, RefCell};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use crate::sys::alloc::{AllocError, try_box, try_vec};
+use crate::sys::sync::SpinLock;
+use self::channel::Waiter;
+use self::discipline::*;
+use self::line_editor::*;
+use self::source::*;
+use self::stats::*;
+use crate::asm;
+
+// room for the bookkeeping of a new operation, checked by the try_ variants
+const OP_HEADROOM: usize = 1024;
+// characters a line can take before its buffer has to grow
+const LINE_CAPACITY: usize = 128;
+
+// an op is moved out of its slot while its callback runs, so the callbacks
+// can add and cancel ops freely
 enum Op {
     ReadLine(
-        // buffer
-        RefCell<Vec<char>>,
-        // callback
-        Box<dyn Fn(String)>
+        SourceId,
+        // the line so far
+        LineEditor,
+        // deadline
+        Option<u64>,
+        // callback, None when the deadline passed
+        Box<dyn Fn(Option<String>)>
     ),
     ReadChar(
+        SourceId,
+        // deadline
+        Option<u64>,
+        // callback, None when the deadline passed
+        Box<dyn Fn(Option<char>)>
+    ),
+    // takes any event of the source
+    ReadEvent(
+        SourceId,
         // callback
-        Box<dyn Fn(char)>
+        Box<dyn Fn(Event)>
     ),
     PutChar(
+        SourceId,
         char,
         // callback
         Box<dyn Fn()>
     ),
     PutBuffer(
+        SourceId,
         // the buffer is in reverse order
-        RefCell<Vec<char>>,
+        Vec<char>,
+        // callback
+        Box<dyn Fn()>
+    ),
+    // sees every event of the source without taking it, until cancelled
+    Tap(
+        SourceId,
+        // callback
+        Box<dyn Fn(Event)>
+    ),
+    Timer(
+        // deadline
+        u64,
+        // period of an interval
+        Option<u64>,
         // callback
         Box<dyn Fn()>
-    )
+    ),
+    // waits for an item or for room on a channel
+    Channel(Box<dyn Waiter>)
 }
 
-struct Handle {
-    op: Op
+impl Op {
+    fn deadline(&self) -> Option<u64> {
+        match self {
+            Op::ReadLine(_, _, deadline, _) => *deadline,
+            Op::ReadChar(_, deadline, _) => *deadline,
+            Op::Timer(deadline, _, _) => Some(*deadline),
+            _ => None,
+        }
+    }
+    fn kind(&self) -> OpKind {
+        match self {
+            Op::ReadLine(..) => OpKind::ReadLine,
+            Op::ReadChar(..) => OpKind::ReadChar,
+            Op::ReadEvent(..) => OpKind::ReadEvent,
+            Op::PutChar(..) => OpKind::PutChar,
+            Op::PutBuffer(..) => OpKind::PutBuffer,
+            Op::Tap(..) => OpKind::Tap,
+            Op::Timer(..) => OpKind::Timer,
+            Op::Channel(..) => OpKind::Channel,
+        }
+    }
+    fn writes_to(&self, source: SourceId) -> bool {
+        match self {
+            Op::PutChar(s, ..) | Op::PutBuffer(s, ..) => *s == source,
+            _ => false,
+        }
+    }
+    fn accepts(&self, event: Event) -> bool {
+        match (self, event) {
+            // an interrupt starts the line over
+            (Op::ReadLine(..), Event::Char(_)) | (Op::ReadLine(..), Event::Interrupt) => true,
+            (Op::ReadChar(..), Event::Char(_)) => true,
+            (Op::ReadEvent(..), _) => true,
+            _ => false,
+        }
+    }
 }
 
+enum State {
+    Free,
+    Pending(Op),
+    // the op waits for the end of the pass
+    Staged,
+    // the callback is running
+    Running,
+    // cancelled while its callback was running
+    Cancelled
+}
+
+struct Slot {
+    // bumped when the slot is freed, the handles of the old op do not match
+    generation: u32,
+    state: State,
+    kind: OpKind,
+    // when the op was added
+    submitted: u64
+}
+
+// identifies a pending operation of the loop, the slot index and generation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpHandle(u64);
+
+impl OpHandle {
+    fn new(index: usize, generation: u32) -> OpHandle {
+        OpHandle((u64::from(generation) << 32) | index as u64)
+    }
+    fn index(self) -> usize {
+        (self.0 & 0xFFFF_FFFF) as usize
+    }
+    fn generation(self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+}
+
+enum Queue {
+    Readers,
+    Writers,
+    Taps
+}
+
+// the ops waiting on a source, in the order they came, the handles of
+// finished ops are dropped when they are come across
+struct Source {
+    device: &'static SpinLock<dyn EventSource>,
+    readers: VecDeque<OpHandle>,
+    writers: VecDeque<OpHandle>,
+    taps: Vec<OpHandle>,
+    // the echo of the readers while no writer waits, reused
+    echo: VecDeque<char>,
+    // what the editor echoes for a key, reused, out while it is edited
+    scratch: Option<String>,
+    // the lines read, shared by the readers of the source, out while one
+    // of them edits with it
+    history: Option<History>,
+    discipline: Discipline
+}
+
+struct Inner {
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+    sources: Vec<Source>,
+    // the ops with a deadline
+    timed: Vec<OpHandle>,
+    // the ops waiting on channels, in the order they came
+    waits: VecDeque<OpHandle>,
+    // the ops added since the last merge
+    staged: Vec<(OpHandle, Op)>,
+    // a device has to be polled for its output
+    dirty: bool,
+    // the earliest deadline of the pending operations
+    deadline: Option<u64>,
+    stats: LoopStats,
+    // callbacks run in the current pass
+    called: u64
+}
+
+impl Inner {
+    fn slot(&mut self, handle: OpHandle) -> Option<&mut Slot> {
+        match self.slots.get_mut(handle.index()) {
+            Some(slot) if slot.generation == handle.generation() => Some(slot),
+            _ => None,
+        }
+    }
+    // a slot for an op that is merged later
+    fn reserve(&mut self, kind: OpKind) -> OpHandle {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    state: State::Free,
+                    kind,
+                    submitted: 0
+                });
+                self.slots.len() - 1
+            }
+        };
+        let slot = &mut self.slots[index];
+        slot.state = State::Staged;
+        slot.kind = kind;
+        slot.submitted = global![timer].now();
+        self.stats.kind_mut(kind).submit();
+        OpHandle::new(index, slot.generation)
+    }
+    // moves the staged ops into their slots and queues, the cancelled ones
+    // are dropped
+    fn merge(&mut self) {
+        let mut staged = core::mem::replace(&mut self.staged, Vec::new());
+        for (handle, op) in staged.drain(..) {
+            match self.slot(handle) {
+                Some(Slot { state: State::Staged, .. }) => {},
+                _ => continue,
+            }
+            let queue = match &op {
+                Op::ReadLine(source, ..) | Op::ReadChar(source, ..) | Op::ReadEvent(source, ..) => {
+                    Some((*source, Queue::Readers))
+                },
+                Op::PutChar(source, ..) | Op::PutBuffer(source, ..) => Some((*source, Queue::Writers)),
+                Op::Tap(source, ..) => Some((*source, Queue::Taps)),
+                Op::Timer(..) | Op::Channel(..) => None,
+            };
+            if let Some((source, queue)) = queue {
+                let source = &mut self.sources[source.0];
+                match queue {
+                    Queue::Readers => source.readers.push_back(handle),
+                    Queue::Writers => source.writers.push_back(handle),
+                    Queue::Taps => source.taps.push(handle),
+                }
+            }
+            // the channel can be ready already
+            if let Op::Channel(..) = op {
+                self.waits.push_back(handle);
+                self.dirty = true;
+            }
+            if let Some(deadline) = op.deadline() {
+                self.timed.push(handle);
+                self.deadline = Some(self.deadline.map_or(deadline, |d| d.min(deadline)));
+            }
+            self.slots[handle.index()].state = State::Pending(op);
+        }
+        // the buffer is kept, the merges do not allocate
+        self.staged = staged;
+    }
+    // moves the op out of its slot to run its callback
+    fn take(&mut self, handle: OpHandle) -> Option<Op> {
+        let slot = self.slot(handle)?;
+        match core::mem::replace(&mut slot.state, State::Running) {
+            State::Pending(op) => Some(op),
+            state => {
+                slot.state = state;
+                None
+            }
+        }
+    }
+    // puts the op back after its callback, it is given back if it was
+    // cancelled meanwhile, to be dropped after the borrow
+    fn restore(&mut self, handle: OpHandle, op: Op) -> Option<Op> {
+        let cancelled = match self.slot(handle) {
+            Some(slot) => match slot.state {
+                State::Running => {
+                    slot.state = State::Pending(op);
+                    None
+                },
+                _ => Some(op),
+            },
+            None => Some(op),
+        };
+        if cancelled.is_some() {
+            self.release(handle, false);
+        }
+        self.called += 1;
+        cancelled
+    }
+    // frees the slot of a cancelled op, or of a finished one before its
+    // callback runs
+    fn release(&mut self, handle: OpHandle, completed: bool) {
+        if let Some(slot) = self.slot(handle) {
+            slot.state = State::Free;
+            slot.generation = slot.generation.wrapping_add(1);
+            let (kind, submitted) = (slot.kind, slot.submitted);
+            self.free.push(handle.index());
+            let stats = self.stats.kind_mut(kind);
+            if completed {
+                stats.complete(global![timer].now() - submitted);
+                self.called += 1;
+            } else {
+                stats.cancel();
+            }
+        }
+    }
+    // writes the echo of the source as far as the device takes it, false if
+    // some is left
+    fn flush_echo(&mut self, i: usize) -> bool {
+        let source = &mut self.sources[i];
+        let mut device = source.device.lock();
+        while let Some(&c) = source.echo.front() {
+            if !device.try_put(c) {
+                break;
+            }
+            source.echo.pop_front();
+        }
+        if source.echo.is_empty() {
+            return true;
+        }
+        // devices without a notification are polled
+        if !device.notify_writable() {
+            self.dirty = true;
+        }
+        false
+    }
+    // the earliest deadline of the pending ops, the finished ones are dropped
+    fn next_deadline(&mut self) -> Option<u64> {
+        let mut deadline = None;
+        let mut t = 0;
+        while t < self.timed.len() {
+            let handle = self.timed[t];
+            match self.slot(handle) {
+                Some(Slot { state: State::Pending(op), .. }) => {
+                    let d = op.deadline().unwrap();
+                    deadline = Some(deadline.map_or(d, |deadline: u64| deadline.min(d)));
+                    t += 1;
+                },
+                // the op of an interval is out while its callback runs
+                Some(Slot { state: State::Running, .. }) => t += 1,
+                _ => {
+                    self.timed.swap_remove(t);
+                },
+            }
+        }
+        deadline
+    }
+}
+
+// a pass only looks at the sources that are ready and the ops that have
+// a deadline once the earliest of them passed, the slots and the queues are
+// reused so the passes do not allocate once they reached their size
+//
+// the methods take &self, the callbacks reach the loop through
+// global![default_loop] while it dispatches, the ops they add are staged and
+// merged after the pass, the state is never borrowed while a callback runs
 pub struct Loop {
-    id: u64,
-    req: Option<BTreeMap<u64, Handle>>,
-    dirty: bool
+    inner: RefCell<Option<Inner>>,
+    // a channel changed, set without borrowing the state
+    notified: Cell<bool>,
+    // the task running the passes, see driver
+    waker: RefCell<Option<Waker>>
 }
 
+// the loop is used by core 0 in thread context only, the interrupt handlers
+// hand their work over with the deferred queue
+unsafe impl Sync for Loop {}
+
 impl Loop {
     pub const fn new() -> Self {
         Loop {
-            id: 0,
-            req: None,
-            dirty: false
+            inner: RefCell::new(None),
+            notified: Cell::new(false),
+            waker: RefCell::new(None)
         }
     }
-    pub fn init(&mut self) {
-        self.req = Some(BTreeMap::new());
+    pub fn init(&self) {
+        self.reset();
+        self.register_source(global![mini_uart]);
+        commands::register();
     }
-    pub fn read_line(&mut self, callback: Box<dyn Fn(String)>) {
-        self.id += 1;
-        self.req.as_mut().unwrap().insert(self.id, Handle {
-            op: Op::ReadLine(
-                RefCell::new(Vec::new()),
-                callback
-            )
+    // the state without any source, the ops of the old one are dropped after
+    // the borrow
+    fn reset(&self) {
+        let old = self.inner.borrow_mut().replace(Inner {
+            slots: Vec::new(),
+            free: Vec::new(),
+            sources: Vec::new(),
+            timed: Vec::new(),
+            waits: VecDeque::new(),
+            staged: Vec::new(),
+            dirty: false,
+            deadline: None,
+            stats: LoopStats::default(),
+            called: 0
         });
-        self.dirty = true;
+        drop(old);
+    }
+    fn with<R, F: FnOnce(&mut Inner) -> R>(&self, f: F) -> R {
+        f(self.inner.borrow_mut().as_mut().unwrap())
+    }
+    // the ops name the source by the returned id
+    pub fn register_source(&self, device: &'static SpinLock<dyn EventSource>) -> SourceId {
+        self.with(move |inner| {
+            inner.sources.push(Source {
+                device,
+                readers: VecDeque::new(),
+                writers: VecDeque::new(),
+                taps: Vec::new(),
+                echo: VecDeque::new(),
+                scratch: Some(String::new()),
+                history: Some(History::new(HISTORY_DEPTH)),
+                discipline: Discipline::default()
+            });
+            SourceId(inner.sources.len() - 1)
+        })
+    }
+    // the lines kept for Up and Down, 0 turns the history off, the readers
+    // showing a line that is dropped go to the oldest one left
+    pub fn set_history_depth(&self, source: SourceId, depth: usize) {
+        self.with(|inner| {
+            let slots = &mut inner.slots;
+            let source = &mut inner.sources[source.0];
+            let history = source.history.as_mut().unwrap();
+            history.set_depth(depth);
+            for &handle in source.readers.iter() {
+                let slot = &mut slots[handle.index()];
+                if slot.generation != handle.generation() {
+                    continue;
+                }
+                if let State::Pending(Op::ReadLine(_, editor, ..)) = &mut slot.state {
+                    editor.clamp_recall(history.len());
+                }
+            }
+        });
+    }
+    pub fn set_discipline(&self, source: SourceId, discipline: Discipline) {
+        self.with(|inner| inner.sources[source.0].discipline = discipline);
+    }
+    // the default one before the source is registered, and while the state is
+    // borrowed, when a panic is printed
+    pub fn discipline(&self, source: SourceId) -> Discipline {
+        match self.inner.try_borrow() {
+            Ok(inner) => inner
+                .as_ref()
+                .and_then(|inner| inner.sources.get(source.0))
+                .map_or(Discipline::default(), |source| source.discipline),
+            Err(_) => Discipline::default(),
+        }
+    }
+    // the characters of s as the device takes them, in reverse order
+    fn output(&self, source: SourceId, s: &str, buffer: &mut Vec<char>) {
+        let discipline = self.discipline(source);
+        for c in s.chars() {
+            discipline.output(c, |c| buffer.push(c));
+        }
+        buffer.reverse();
+    }
+    fn try_output(&self, source: SourceId, s: &str) -> Result<Vec<char>, AllocError> {
+        let discipline = self.discipline(source);
+        let mut count = 0;
+        for c in s.chars() {
+            discipline.output(c, |_| count += 1);
+        }
+        let mut buffer = try_vec(count)?;
+        self.output(source, s, &mut buffer);
+        Ok(buffer)
     }
-    pub fn read_char(&mut self, callback: Box<dyn Fn(char)>) {
-        self.id += 1;
-        self.req.as_mut().unwrap().insert(self.id, Handle {
-            op: Op::ReadChar(callback)
+    // the echo of a reader, after the output queued so far, it only needs an
+    // op of its own while writers wait
+    fn echo(&self, source: SourceId, echo: &str, done: bool) {
+        let discipline = self.discipline(source);
+        let echo = discipline.echo(echo, done);
+        if echo.is_empty() {
+            return;
+        }
+        let buffered = self.with(|inner| {
+            let waiting = !inner.sources[source.0].writers.is_empty()
+                || inner.staged.iter().any(|(_, op)| op.writes_to(source));
+            if waiting {
+                return false;
+            }
+            let buffer = &mut inner.sources[source.0].echo;
+            for c in echo.chars() {
+                discipline.output(c, |c| buffer.push_back(c));
+            }
+            inner.flush_echo(source.0);
+            true
         });
-        self.dirty = true;
+        if !buffered {
+            let mut buffer = Vec::new();
+            self.output(source, echo, &mut buffer);
+            self.push(Op::PutBuffer(source, buffer, Box::new(|| {})));
+        }
     }
-    pub fn put_char(&mut self, c: char, callback: Box<dyn Fn()>) {
-        self.id += 1;
-        self.req.as_mut().unwrap().insert(self.id, Handle {
-            op: Op::PutChar(c, callback)
+    fn push(&self, op: Op) -> OpHandle {
+        let handle = self.with(|inner| {
+            let handle = inner.reserve(op.kind());
+            inner.staged.push((handle, op));
+            handle
         });
-        self.dirty = true;
-    }
-    pub fn put_string(&mut self, s: String, callback: Box<dyn Fn()>) {
-        self.id += 1;
-        self.req.as_mut().unwrap().insert(self.id, Handle {
-            op: Op::PutBuffer(
-                RefCell::new(s.chars().rev().collect()),
-                callback
-            )
+        self.wake();
+        handle
+    }
+    // the callback of the op will not be called, false if it is not pending
+    // anymore, callbacks can cancel other ops and themselves as well
+    pub fn cancel(&self, handle: OpHandle) -> bool {
+        // the op is dropped after the borrow, its captures can use the loop
+        let mut dropped = None;
+        let cancelled = self.with(|inner| {
+            let slot = match inner.slot(handle) {
+                Some(slot) => slot,
+                None => return false,
+            };
+            match core::mem::replace(&mut slot.state, State::Free) {
+                State::Pending(op) => {
+                    dropped = Some(op);
+                    inner.release(handle, false);
+                    true
+                },
+                State::Staged => {
+                    let staged = inner.staged.iter().position(|&(staged, _)| staged == handle);
+                    dropped = staged.map(|i| inner.staged.remove(i).1);
+                    inner.release(handle, false);
+                    true
+                },
+                State::Running => {
+                    slot.state = State::Cancelled;
+                    true
+                },
+                state => {
+                    slot.state = state;
+                    false
+                },
+            }
         });
-        self.dirty = true;
+        drop(dropped);
+        cancelled
+    }
+    fn wait(&self, waiter: Box<dyn Waiter>) -> OpHandle {
+        self.push(Op::Channel(waiter))
+    }
+    // the ops waiting on channels are checked in the next pass
+    fn notify(&self) {
+        self.notified.set(true);
+        self.wake();
+    }
+    // the next pass runs with the next run of the executor, called by the
+    // bottom halves of the interrupts and when an op is added
+    pub fn wake(&self) {
+        if let Some(waker) = self.waker.borrow().as_ref() {
+            waker.wake_by_ref();
+        }
+    }
+    // the task running the passes on the executor, it sleeps until something
+    // wakes it, the interrupts through their bottom halves
+    pub fn driver(&'static self) -> Driver {
+        Driver(self)
+    }
+    // the deadline in ticks, the given milliseconds from now
+    fn deadline_in(ms: u64) -> u64 {
+        let timer = global![timer];
+        timer.now() + timer.ticks(ms)
+    }
+    pub fn read_line(&self, callback: Box<dyn Fn(String)>) -> OpHandle {
+        self.read_line_from(CONSOLE, callback)
+    }
+    pub fn read_line_from(&self, source: SourceId, callback: Box<dyn Fn(String)>) -> OpHandle {
+        self.push(Op::ReadLine(
+            source,
+            LineEditor::new(Vec::new()),
+            None,
+            Box::new(move |line| callback(line.unwrap()))
+        ))
+    }
+    // like read_line, but fails instead of panicking when memory is low
+    pub fn try_read_line<F: Fn(String) + 'static>(&self, callback: F) -> Result<OpHandle, AllocError> {
+        self.try_read_line_inner(None, LineOptions::default(), move |line| callback(line.unwrap()))
+    }
+    // writes the prompt first, Tab asks the completions of the options
+    pub fn read_line_with(&self, options: LineOptions, callback: Box<dyn Fn(String)>) -> OpHandle {
+        if !options.prompt.is_empty() {
+            self.put_string(options.prompt.clone(), Box::new(|| {}));
+        }
+        self.push(Op::ReadLine(
+            CONSOLE,
+            LineEditor::with_options(Vec::new(), options),
+            None,
+            Box::new(move |line| callback(line.unwrap()))
+        ))
+    }
+    pub fn try_read_line_with<F>(&self, options: LineOptions, callback: F) -> Result<OpHandle, AllocError>
+    where
+        F: Fn(String) + 'static
+    {
+        self.try_read_line_inner(None, options, move |line| callback(line.unwrap()))
+    }
+    // gives up after the given milliseconds, the callback gets None then
+    pub fn read_line_timeout(&self, ms: u64, callback: Box<dyn Fn(Option<String>)>) -> OpHandle {
+        self.push(Op::ReadLine(
+            CONSOLE,
+            LineEditor::new(Vec::new()),
+            Some(Loop::deadline_in(ms)),
+            callback
+        ))
+    }
+    pub fn try_read_line_timeout<F>(&self, ms: u64, callback: F) -> Result<OpHandle, AllocError>
+    where
+        F: Fn(Option<String>) + 'static
+    {
+        self.try_read_line_inner(Some(Loop::deadline_in(ms)), LineOptions::default(), callback)
+    }
+    fn try_read_line_inner<F>(
+        &self,
+        deadline: Option<u64>,
+        options: LineOptions,
+        callback: F
+    ) -> Result<OpHandle, AllocError>
+    where
+        F: Fn(Option<String>) + 'static
+    {
+        global![allocator].check_headroom(OP_HEADROOM)?;
+        let buffer = try_vec(LINE_CAPACITY)?;
+        let callback = try_box(callback)?;
+        if !options.prompt.is_empty() {
+            self.try_put_string(&options.prompt, || {})?;
+        }
+        let handle = self.push(Op::ReadLine(
+            CONSOLE,
+            LineEditor::with_options(buffer, options),
+            deadline,
+            callback
+        ));
+        Ok(handle)
+    }
+    pub fn read_char(&self, callback: Box<dyn Fn(char)>) -> OpHandle {
+        self.read_char_from(CONSOLE, callback)
+    }
+    pub fn read_char_from(&self, source: SourceId, callback: Box<dyn Fn(char)>) -> OpHandle {
+        self.push(Op::ReadChar(source, None, Box::new(move |c| callback(c.unwrap()))))
+    }
+    // like read_char, but fails instead of panicking when memory is low
+    pub fn try_read_char<F: Fn(char) + 'static>(&self, callback: F) -> Result<OpHandle, AllocError> {
+        self.try_read_char_inner(None, move |c| callback(c.unwrap()))
+    }
+    // gives up after the given milliseconds, the callback gets None then
+    pub fn read_char_timeout(&self, ms: u64, callback: Box<dyn Fn(Option<char>)>) -> OpHandle {
+        self.push(Op::ReadChar(CONSOLE, Some(Loop::deadline_in(ms)), callback))
+    }
+    pub fn try_read_char_timeout<F>(&self, ms: u64, callback: F) -> Result<OpHandle, AllocError>
+    where
+        F: Fn(Option<char>) + 'static
+    {
+        self.try_read_char_inner(Some(Loop::deadline_in(ms)), callback)
+    }
+    fn try_read_char_inner<F>(&self, deadline: Option<u64>, callback: F) -> Result<OpHandle, AllocError>
+    where
+        F: Fn(Option<char>) + 'static
+    {
+        global![allocator].check_headroom(OP_HEADROOM)?;
+        let callback = try_box(callback)?;
+        let handle = self.push(Op::ReadChar(CONSOLE, deadline, callback));
+        Ok(handle)
+    }
+    // the next event of the source, whatever it is
+    pub fn read_event(&self, source: SourceId, callback: Box<dyn Fn(Event)>) -> OpHandle {
+        self.push(Op::ReadEvent(source, callback))
+    }
+    // calls back with every character read, next to the readers
+    pub fn tap(&self, callback: Box<dyn Fn(char)>) -> OpHandle {
+        self.watch(CONSOLE, Box::new(move |event| {
+            if let Event::Char(c) = event {
+                callback(c);
+            }
+        }))
+    }
+    pub fn try_tap<F: Fn(char) + 'static>(&self, callback: F) -> Result<OpHandle, AllocError> {
+        global![allocator].check_headroom(OP_HEADROOM)?;
+        let callback = try_box(move |event| {
+            if let Event::Char(c) = event {
+                callback(c);
+            }
+        })?;
+        let handle = self.push(Op::Tap(CONSOLE, callback));
+        Ok(handle)
+    }
+    // calls back with every event of the source, next to the readers
+    pub fn watch(&self, source: SourceId, callback: Box<dyn Fn(Event)>) -> OpHandle {
+        self.push(Op::Tap(source, callback))
+    }
+    pub fn put_char(&self, c: char, callback: Box<dyn Fn()>) -> OpHandle {
+        let mut buffer = Vec::new();
+        self.output(CONSOLE, c.encode_utf8(&mut [0; 4]), &mut buffer);
+        // the discipline can make more of it
+        if buffer.len() == 1 {
+            self.push(Op::PutChar(CONSOLE, c, callback))
+        } else {
+            self.push(Op::PutBuffer(CONSOLE, buffer, callback))
+        }
+    }
+    pub fn put_string(&self, s: String, callback: Box<dyn Fn()>) -> OpHandle {
+        self.put_string_to(CONSOLE, s, callback)
+    }
+    pub fn put_string_to(&self, source: SourceId, s: String, callback: Box<dyn Fn()>) -> OpHandle {
+        let mut buffer = Vec::new();
+        self.output(source, &s, &mut buffer);
+        self.push(Op::PutBuffer(source, buffer, callback))
+    }
+    // like put_string, but fails instead of panicking when memory is low
+    pub fn try_put_string<F: Fn() + 'static>(&self, s: &str, callback: F) -> Result<OpHandle, AllocError> {
+        global![allocator].check_headroom(OP_HEADROOM)?;
+        let buffer = self.try_output(CONSOLE, s)?;
+        let callback = try_box(callback)?;
+        let handle = self.push(Op::PutBuffer(
+            CONSOLE,
+            buffer,
+            callback
+        ));
+        Ok(handle)
+    }
+    // calls back once, after the given milliseconds
+    pub fn set_timeout(&self, ms: u64, callback: Box<dyn Fn()>) -> OpHandle {
+        self.push(Op::Timer(Loop::deadline_in(ms), None, callback))
+    }
+    // calls back every given milliseconds
+    pub fn set_interval(&self, ms: u64, callback: Box<dyn Fn()>) -> OpHandle {
+        let period = global![timer].ticks(ms).max(1);
+        self.push(Op::Timer(Loop::deadline_in(ms), Some(period), callback))
+    }
+    pub fn try_set_timeout<F: Fn() + 'static>(&self, ms: u64, callback: F) -> Result<OpHandle, AllocError> {
+        global![allocator].check_headroom(OP_HEADROOM)?;
+        let callback = try_box(callback)?;
+        let handle = self.push(Op::Timer(Loop::deadline_in(ms), None, callback));
+        Ok(handle)
     }
     pub fn is_dirty(&self) -> bool {
-        self.dirty || global![mini_uart].character_available()
-    }
-    pub fn run_inner(&mut self) {
-        self.dirty = false;
-        let mut dirty = false;
-        let character = global![mini_uart].try_get_char();
-        let called: Vec<u64> = self.req
-            .as_ref()
-            .unwrap()
-            .iter()
-            .filter_map(|(id, handle)| {
-                match &handle.op {
-                    Op::ReadChar(callback) => {
-                        if let Some(mut c) = character {
-                            if c == '\r' {
-                                c = '\n';
-                            }
-                            callback(c);
-                            return Some(*id);
+        if !global![deferred].is_empty() {
+            return true;
+        }
+        if self.notified.get() {
+            return true;
+        }
+        let now = global![timer].now();
+        self.with(|inner| {
+            inner.dirty
+                || !inner.staged.is_empty()
+                || inner.deadline.map_or(false, |deadline| now >= deadline)
+                || inner.sources.iter().any(|source| {
+                    (!source.readers.is_empty() || !source.taps.is_empty()) && source.device.lock().ready()
+                })
+        })
+    }
+    pub fn run_inner(&self) {
+        let start = global![timer].now();
+        // the bottom halves first, they feed the sources
+        let deferred = global![deferred].run();
+        self.notified.set(false);
+        let sources = self.with(|inner| {
+            inner.dirty = false;
+            inner.called = 0;
+            inner.merge();
+            inner.sources.len()
+        });
+        for i in 0..sources {
+            self.run_writers(i);
+            self.run_readers(i);
+        }
+        self.run_waits();
+        let now = global![timer].now();
+        if self.with(|inner| inner.deadline.map_or(false, |deadline| now >= deadline)) {
+            self.run_timed(now);
+        }
+        self.with(|inner| {
+            inner.merge();
+            inner.deadline = inner.next_deadline();
+            // the timer wakes up the core for the next deadline
+            match inner.deadline {
+                Some(deadline) => global![timer].arm(deadline),
+                None => global![timer].disarm(),
+            }
+            inner.stats.passes += 1;
+            if inner.called == 0 && deferred == 0 {
+                inner.stats.empty_passes += 1;
+            }
+            inner.stats.busy += global![timer].now() - start;
+        });
+    }
+    // sleeps until an interrupt if nothing can progress, an interrupt between
+    // the check and the wfi still wakes the core, it is taken after the mask
+    // is restored
+    pub fn idle(&self) {
+        let daif = asm::irq_save();
+        if !self.is_dirty() {
+            let start = global![timer].now();
+            asm::wfi();
+            let slept = global![timer].now() - start;
+            self.with(|inner| {
+                inner.stats.idle += slept;
+                inner.stats.sleeps += 1;
+            });
+        }
+        asm::irq_restore(daif);
+    }
+    pub fn stats(&self) -> LoopStats {
+        let mut stats = self.with(|inner| inner.stats);
+        stats.deferred_overflows = global![deferred].overflows();
+        stats.deferred_high_water = global![deferred].high_water();
+        stats
+    }
+    // the writers go one after the other, as far as the device takes the
+    // characters, then the device tells when it can take more
+    fn run_writers(&self, i: usize) {
+        // the echo came before the writers
+        if !self.with(|inner| inner.flush_echo(i)) {
+            return;
+        }
+        loop {
+            let done = self.with(|inner| {
+                let handle = *inner.sources[i].writers.front()?;
+                let op = match inner.take(handle) {
+                    Some(op) => op,
+                    None => {
+                        inner.sources[i].writers.pop_front();
+                        return Some(None);
+                    }
+                };
+                let mut device = inner.sources[i].device.lock();
+                // the callback when the op is done, the op when it has to wait
+                let done = match op {
+                    Op::PutChar(source, c, callback) => {
+                        if device.try_put(c) {
+                            Ok(callback)
+                        } else {
+                            Err(Op::PutChar(source, c, callback))
                         }
-                        None
                     },
-                    Op::ReadLine(buffer, callback) => {
-                        if let Some(mut c) = character {
-                            if c == '\r' {
-                                c = '\n';
-                            }
-                            if c == '\n' {
-                                callback(buffer.borrow().iter().collect());
-                                return Some(*id);
-                            } else {
-                                buffer.borrow_mut().push(c);
+                    Op::PutBuffer(source, mut buffer, callback) => {
+                        while let Some(&c) = buffer.last() {
+                            if !device.try_put(c) {
+                                break;
                             }
+                            buffer.pop();
                         }
-                        None
-                    },
-                    Op::PutChar(c, callback) => {
-                        if global![mini_uart].try_put_char(*c) {
-                            callback();
-                            return Some(*id);
+                        if buffer.is_empty() {
+                            Ok(callback)
+                        } else {
+                            Err(Op::PutBuffer(source, buffer, callback))
                         }
-                        dirty = true;
-                        None
                     },
-                    Op::PutBuffer(buffer, callback) => {
-                        let mut b = buffer.borrow_mut();
-                        if b.is_empty() {
-                            callback();
-                            return Some(*id);
+                    _ => unreachable!(),
+                };
+                match done {
+                    Err(op) => {
+                        inner.slots[handle.index()].state = State::Pending(op);
+                        // devices without a notification are polled
+                        if !device.notify_writable() {
+                            inner.dirty = true;
                         }
-                        let c = b.last().unwrap();
-                        if global![mini_uart].try_put_char(*c) {
-                            b.pop();
-                            if b.is_empty() {    
-                                callback();
-                                return Some(*id);
-                            }
-                        }
-                        dirty = true;
                         None
                     },
+                    Ok(callback) => {
+                        inner.sources[i].writers.pop_front();
+                        inner.release(handle, true);
+                        Some(Some(callback))
+                    },
                 }
-            })
-            .collect();
-        if dirty {
-            self.dirty = true;
+            });
+            match done {
+                Some(Some(callback)) => callback(),
+                Some(None) => {},
+                None => return,
+            }
+        }
+    }
+    // one event per pass, the taps see it and the first reader that accepts
+    // it takes it, it is dropped if nobody reads, the device keeps it if
+    // nobody waits on it
+    fn run_readers(&self, i: usize) {
+        let event = self.with(|inner| {
+            let slots = &inner.slots;
+            let source = &mut inner.sources[i];
+            source.readers.retain(|&handle| slots[handle.index()].generation == handle.generation());
+            if source.readers.is_empty() && source.taps.is_empty() {
+                return None;
+            }
+            let event = source.device.lock().poll();
+            match event {
+                Some(Event::Char(c)) => Some(source.discipline.input(c)),
+                event => event,
+            }
+        });
+        let event = match event {
+            Some(event) => event,
+            None => return,
+        };
+        let mut t = 0;
+        while let Some(handle) = self.with(|inner| inner.sources[i].taps.get(t).cloned()) {
+            let op = self.with(|inner| inner.take(handle));
+            if let Some(Op::Tap(source, callback)) = op {
+                callback(event);
+                if self.with(|inner| inner.restore(handle, Op::Tap(source, callback))).is_none() {
+                    t += 1;
+                    continue;
+                }
+            }
+            self.with(|inner| inner.sources[i].taps.remove(t));
         }
-        for id in called {
-            self.req.as_mut().unwrap().remove(&id);
+        // the first reader that accepts the event
+        let reader = self.with(|inner| {
+            let mut r = 0;
+            while r < inner.sources[i].readers.len() {
+                let handle = inner.sources[i].readers[r];
+                let accepts = match inner.slot(handle) {
+                    Some(Slot { state: State::Pending(op), .. }) => op.accepts(event),
+                    _ => false,
+                };
+                if accepts {
+                    let op = inner.take(handle).unwrap();
+                    return Some((r, handle, op));
+                }
+                r += 1;
+            }
+            None
+        });
+        let (r, handle, op) = match reader {
+            Some(reader) => reader,
+            None => return,
+        };
+        match (op, event) {
+            (Op::ReadLine(source, mut editor, deadline, callback), Event::Interrupt) => {
+                let mut echo = self.with(|inner| inner.sources[i].scratch.take().unwrap());
+                echo.clear();
+                editor.interrupt(&mut echo);
+                self.echo(source, &echo, true);
+                self.with(|inner| {
+                    inner.sources[i].scratch = Some(echo);
+                    inner.restore(handle, Op::ReadLine(source, editor, deadline, callback))
+                });
+            },
+            (Op::ReadLine(source, mut editor, deadline, callback), Event::Char(c)) => {
+                // the completions run in feed, they can use the loop
+                let (mut history, mut echo) = self.with(|inner| {
+                    let source = &mut inner.sources[i];
+                    (source.history.take().unwrap(), source.scratch.take().unwrap())
+                });
+                echo.clear();
+                let line = editor.feed(&mut history, c, &mut echo);
+                self.with(|inner| inner.sources[i].history = Some(history));
+                self.echo(source, &echo, line.is_some());
+                self.with(|inner| inner.sources[i].scratch = Some(echo));
+                match line {
+                    Some(line) => {
+                        self.finish(i, r, handle);
+                        callback(Some(line));
+                    },
+                    None => {
+                        self.with(|inner| inner.restore(handle, Op::ReadLine(source, editor, deadline, callback)));
+                    },
+                }
+            },
+            (Op::ReadChar(source, _, callback), Event::Char(c)) => {
+                if self.discipline(source).echo_chars && (!c.is_control() || c == '\n') {
+                    self.echo(source, c.encode_utf8(&mut [0; 4]), false);
+                }
+                self.finish(i, r, handle);
+                callback(Some(c));
+            },
+            (Op::ReadEvent(_, callback), event) => {
+                self.finish(i, r, handle);
+                callback(event);
+            },
+            _ => unreachable!(),
+        }
+    }
+    // the waits that are ready are done in the order they came, the others
+    // keep their place
+    fn run_waits(&self) {
+        let mut w = 0;
+        loop {
+            let waiter = self.with(|inner| {
+                while let Some(&handle) = inner.waits.get(w) {
+                    let ready = match inner.slot(handle) {
+                        Some(Slot { state: State::Pending(Op::Channel(waiter)), .. }) => waiter.ready(),
+                        Some(Slot { state: State::Pending(_), .. }) => unreachable!(),
+                        Some(_) => false,
+                        None => {
+                            inner.waits.remove(w);
+                            continue;
+                        }
+                    };
+                    if ready {
+                        inner.waits.remove(w);
+                        let op = inner.take(handle);
+                        inner.release(handle, true);
+                        return op;
+                    }
+                    w += 1;
+                }
+                None
+            });
+            match waiter {
+                Some(Op::Channel(waiter)) => waiter.complete(),
+                _ => return,
+            }
+        }
+    }
+    // the reader took its event
+    fn finish(&self, i: usize, r: usize, handle: OpHandle) {
+        self.with(|inner| {
+            inner.sources[i].readers.remove(r);
+            inner.release(handle, true);
+        });
+    }
+    // calls back the ops whose deadline passed, the finished ones stay in the
+    // reader queues until they are come across
+    fn run_timed(&self, now: u64) {
+        let mut t = 0;
+        while let Some(handle) = self.with(|inner| inner.timed.get(t).cloned()) {
+            let op = self.with(|inner| {
+                let expired = match inner.slot(handle) {
+                    Some(Slot { state: State::Pending(op), .. }) => now >= op.deadline().unwrap(),
+                    _ => false,
+                };
+                if expired {
+                    inner.take(handle)
+                } else {
+                    None
+                }
+            });
+            let op = match op {
+                Some(op) => op,
+                None => {
+                    t += 1;
+                    continue;
+                }
+            };
+            match op {
+                Op::Timer(deadline, Some(period), callback) => {
+                    callback();
+                    // the next tick after now, missed ones are skipped
+                    let late = (now - deadline) / period + 1;
+                    let next = Op::Timer(deadline + late * period, Some(period), callback);
+                    self.with(|inner| inner.restore(handle, next));
+                },
+                Op::Timer(_, None, callback) => {
+                    self.with(|inner| inner.release(handle, true));
+                    callback();
+                },
+                Op::ReadLine(_, _, _, callback) => {
+                    self.with(|inner| inner.release(handle, true));
+                    callback(None);
+                },
+                Op::ReadChar(_, _, callback) => {
+                    self.with(|inner| inner.release(handle, true));
+                    callback(None);
+                },
+                _ => unreachable!(),
+            }
+            t += 1;
         }
     }
-    pub fn run(&mut self) {
+    pub fn run(&self) {
         while {
             self.run_inner();
             self.is_dirty()
         } {}
     }
 }
+
+pub struct Driver(&'static Loop);
+
+impl Future for Driver {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        self.0.run_inner();
+        *self.0.waker.borrow_mut() = Some(context.waker().clone());
+        // a device that is polled, or a deadline that passed during the pass
+        if self.0.is_dirty() {
+            context.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::rc::Rc;
+    use crate::sys::sync::{enable_multi_core, SpinLock, SpinLockGuard};
+    use crate::sys::alloc::counting;
+
+    // the loops share the timer, the tests take turns
+    static TURN: SpinLock<()> = SpinLock::new(());
+
+    // the try_ ops check the headroom, the heap is set up by the first test
+    pub(super) fn turn() -> SpinLockGuard<'static, ()> {
+        const SIZE: usize = 4 << 20;
+        enable_multi_core();
+        let turn = TURN.lock();
+        if global![allocator].pages().1 == 0 {
+            let memory = Box::leak(vec![0u8; 2 * SIZE].into_boxed_slice());
+            let start = (memory.as_mut_ptr() as usize + SIZE - 1) & !(SIZE - 1);
+            unsafe {
+                global![allocator].add_memory(start, start + SIZE, &[]);
+            }
+        }
+        turn
+    }
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    // a console fed by the test, it takes all of the output
+    pub(super) struct Fake(pub(super) Rc<RefCell<VecDeque<Event>>>, pub(super) Rc<RefCell<String>>);
+
+    impl EventSource for Fake {
+        fn ready(&self) -> bool {
+            !self.0.borrow().is_empty()
+        }
+        fn poll(&mut self) -> Option<Event> {
+            self.0.borrow_mut().pop_front()
+        }
+        fn try_put(&mut self, c: char) -> bool {
+            self.1.borrow_mut().push(c);
+            true
+        }
+    }
+
+    // takes one character every other pass and can not tell when it takes
+    // more, the loop polls it
+    struct Slow(Rc<RefCell<String>>, bool);
+
+    impl EventSource for Slow {
+        fn ready(&self) -> bool {
+            false
+        }
+        fn poll(&mut self) -> Option<Event> {
+            None
+        }
+        fn try_put(&mut self, c: char) -> bool {
+            self.1 = !self.1;
+            if self.1 {
+                self.0.borrow_mut().push(c);
+            }
+            self.1
+        }
+    }
+
+    struct Test {
+        _turn: SpinLockGuard<'static, ()>,
+        l: &'static Loop,
+        input: Rc<RefCell<VecDeque<Event>>>,
+        output: Rc<RefCell<String>>,
+        log: Log,
+    }
+
+    impl Test {
+        // a loop with the fake as its console, the callbacks reach it like
+        // they reach global![default_loop]
+        fn new() -> Test {
+            let turn = turn();
+            let l: &'static Loop = Box::leak(Box::new(Loop::new()));
+            l.reset();
+            let input = Rc::new(RefCell::new(VecDeque::new()));
+            let output = Rc::new(RefCell::new(String::new()));
+            let console = Fake(input.clone(), output.clone());
+            assert_eq!(l.register_source(Box::leak(Box::new(SpinLock::new(console)))), CONSOLE);
+            Test {
+                _turn: turn,
+                l,
+                input,
+                output,
+                log: Rc::new(RefCell::new(Vec::new())),
+            }
+        }
+        fn feed(&self, s: &str) {
+            self.input.borrow_mut().extend(s.chars().map(Event::Char));
+            self.l.run();
+        }
+        // a callback that logs the characters under the given name
+        fn reader(&self, name: &'static str) -> Box<dyn Fn(char)> {
+            let log = self.log.clone();
+            Box::new(move |c| log.borrow_mut().push(format!("{} {}", name, c)))
+        }
+        fn log(&self) -> Vec<String> {
+            self.log.borrow_mut().drain(..).collect()
+        }
+        fn output(&self) -> String {
+            self.output.borrow_mut().drain(..).collect()
+        }
+    }
+
+    #[test]
+    fn cancel_pending() {
+        let t = Test::new();
+        let a = t.l.read_char(t.reader("a"));
+        t.l.read_char(t.reader("b"));
+        t.l.run();
+        assert!(t.l.cancel(a));
+        assert!(!t.l.cancel(a));
+        t.feed("x");
+        assert_eq!(t.log(), ["b x"]);
+        let stats = t.l.stats();
+        let stats = stats.kind(OpKind::ReadChar);
+        assert_eq!((stats.submitted, stats.completed, stats.cancelled), (2, 1, 1));
+    }
+
+    #[test]
+    fn stale_handles() {
+        let t = Test::new();
+        let old = t.l.read_char(t.reader("old"));
+        t.feed("x");
+        // the slot is reused with the next generation
+        let new = t.l.read_char(t.reader("new"));
+        assert_eq!(old.index(), new.index());
+        assert_ne!(old, new);
+        assert!(!t.l.cancel(old));
+        t.feed("y");
+        assert_eq!(t.log(), ["old x", "new y"]);
+        assert!(!t.l.cancel(new));
+    }
+
+    #[test]
+    fn fifo_and_taps() {
+        let t = Test::new();
+        let tap = t.l.tap(t.reader("tap"));
+        t.l.read_char(t.reader("a"));
+        t.l.read_char(t.reader("b"));
+        // the taps see the characters nobody reads as well
+        t.feed("xyz");
+        assert_eq!(t.log(), ["tap x", "a x", "tap y", "b y", "tap z"]);
+        let log = t.log.clone();
+        t.l.read_line(Box::new(move |line| log.borrow_mut().push(format!("line {}", line))));
+        t.l.read_char(t.reader("c"));
+        t.feed("ab\r");
+        assert_eq!(t.log(), ["tap a", "tap b", "tap \n", "line ab"]);
+        assert!(t.l.cancel(tap));
+        t.feed("d");
+        assert_eq!(t.log(), ["c d"]);
+    }
+
+    #[test]
+    fn cancel_staged() {
+        let t = Test::new();
+        // not merged before the next pass
+        let staged = t.l.read_char(t.reader("staged"));
+        assert!(t.l.cancel(staged));
+        assert!(!t.l.cancel(staged));
+        t.feed("x");
+        assert_eq!(t.log(), Vec::<String>::new());
+        // the device kept it, nobody waited
+        t.l.read_char(t.reader("a"));
+        t.l.run();
+        assert_eq!(t.log(), ["a x"]);
+    }
+
+    #[test]
+    fn cancel_running() {
+        let t = Test::new();
+        let handle = Rc::new(Cell::new(None));
+        let (l, h, log) = (t.l, handle.clone(), t.log.clone());
+        handle.set(Some(t.l.tap(Box::new(move |c| {
+            log.borrow_mut().push(format!("tap {}", c));
+            // the tap cancels itself while it runs, only once
+            assert!(l.cancel(h.get().unwrap()));
+            assert!(!l.cancel(h.get().unwrap()));
+        }))));
+        t.l.read_char(t.reader("a"));
+        t.feed("xy");
+        assert_eq!(t.log(), ["tap x", "a x"]);
+        assert!(!t.l.cancel(handle.get().unwrap()));
+        let stats = t.l.stats();
+        assert_eq!(stats.kind(OpKind::Tap).cancelled, 1);
+    }
+
+    #[test]
+    fn staged_merge_order() {
+        let t = Test::new();
+        let (l, log) = (t.l, t.log.clone());
+        let first = Cell::new(true);
+        t.l.tap(Box::new(move |c| {
+            log.borrow_mut().push(format!("tap {}", c));
+            if first.replace(false) {
+                // merged after the pass in the order they came, the cancelled
+                // one is dropped, the character of the pass is not theirs
+                let cancelled = l.read_char(Box::new(|_| panic!("cancelled")));
+                assert!(l.cancel(cancelled));
+                for &name in ["b", "c"].iter() {
+                    let log = log.clone();
+                    l.read_char(Box::new(move |c| log.borrow_mut().push(format!("{} {}", name, c))));
+                }
+            }
+        }));
+        t.l.read_char(t.reader("a"));
+        t.feed("xyz");
+        assert_eq!(t.log(), ["tap x", "a x", "tap y", "b y", "tap z", "c z"]);
+    }
+
+    #[test]
+    fn sources() {
+        let t = Test::new();
+        let signals = Rc::new(RefCell::new(VecDeque::new()));
+        let pin = t.l.register_source(Box::leak(Box::new(SpinLock::new(Fake(signals.clone(), t.output.clone())))));
+        let log = t.log.clone();
+        t.l.watch(pin, Box::new(move |event| log.borrow_mut().push(format!("watch {:?}", event))));
+        let log = t.log.clone();
+        t.l.read_event(pin, Box::new(move |event| log.borrow_mut().push(format!("event {:?}", event))));
+        t.l.read_char(t.reader("a"));
+        signals.borrow_mut().push_back(Event::Signal(7));
+        t.feed("x");
+        assert_eq!(t.log(), ["a x", "watch Signal(7)", "event Signal(7)"]);
+    }
+
+    #[test]
+    fn polled_writers() {
+        let t = Test::new();
+        let output = Rc::new(RefCell::new(String::new()));
+        let slow = t.l.register_source(Box::leak(Box::new(SpinLock::new(Slow(output.clone(), false)))));
+        for &s in ["ab", "c"].iter() {
+            let log = t.log.clone();
+            t.l.put_string_to(slow, s.to_string(), Box::new(move || log.borrow_mut().push(format!("put {}", s))));
+        }
+        t.l.run();
+        // one after the other, the passes went on until the device took all
+        assert_eq!(*output.borrow(), "abc");
+        assert_eq!(t.log(), ["put ab", "put c"]);
+        assert!(!t.l.is_dirty());
+    }
+
+    #[test]
+    fn completions_use_the_loop() {
+        let t = Test::new();
+        let l = t.l;
+        let completions = move |line: &str, start: usize| {
+            // the state is not borrowed while the editor runs
+            l.stats();
+            match &line[..start] {
+                "" => vec!["pin".to_string(), "ping".to_string()],
+                "pin " => vec!["17".to_string(), "27".to_string()],
+                _ => Vec::new(),
+            }
+        };
+        let options = LineOptions {
+            prompt: String::new(),
+            completions: Some(Rc::new(completions)),
+        };
+        let log = t.log.clone();
+        t.l.read_line_with(options, Box::new(move |line| log.borrow_mut().push(line)));
+        t.feed("pin \t2\t\r");
+        assert_eq!(t.log(), ["pin 27 "]);
+    }
+
+    #[test]
+    fn echo() {
+        let t = Test::new();
+        let log = t.log.clone();
+        t.l.read_line(Box::new(move |line| log.borrow_mut().push(line)));
+        t.feed("ab\r");
+        assert_eq!(t.output(), "ab\r\n");
+        // the characters are only echoed if asked for
+        t.l.read_char(t.reader("a"));
+        t.feed("x");
+        assert_eq!(t.output(), "");
+        let discipline = Discipline { echo_chars: true, ..Discipline::default() };
+        t.l.set_discipline(CONSOLE, discipline);
+        t.l.read_char(t.reader("b"));
+        t.feed("y");
+        assert_eq!(t.output(), "y");
+        // after the output queued before it in the same pass
+        let l = t.l;
+        let first = Cell::new(true);
+        t.l.tap(Box::new(move |_| {
+            if first.replace(false) {
+                l.put_string("!".to_string(), Box::new(|| {}));
+            }
+        }));
+        t.l.read_char(t.reader("c"));
+        t.l.read_char(t.reader("d"));
+        t.feed("zw");
+        assert_eq!(t.output(), "!zw");
+        assert_eq!(t.log(), ["ab", "a x", "b y", "c z", "d w"]);
+    }
+
+    #[test]
+    fn shrunk_history() {
+        let t = Test::new();
+        let log = t.log.clone();
+        for _ in 0..4 {
+            let log = log.clone();
+            t.l.read_line(Box::new(move |line| log.borrow_mut().push(line)));
+        }
+        t.feed("one\rtwo\rthree\r");
+        // back to the oldest, then it is dropped
+        t.feed("x\x1b[A\x1b[A\x1b[A");
+        t.l.set_history_depth(CONSOLE, 2);
+        t.feed("\x1b[B\r");
+        assert_eq!(t.log(), ["one", "two", "three", "three"]);
+    }
+
+    #[test]
+    fn typing_does_not_allocate() {
+        let t = Test::new();
+        t.l.read_line(Box::new(|_| {}));
+        // the buffers grow to fit the line first
+        t.feed("abcde");
+        t.output();
+        let allocations = counting::allocations();
+        for c in "fgh".chars() {
+            t.feed(c.encode_utf8(&mut [0; 4]));
+        }
+        assert_eq!(counting::allocations(), allocations);
+        assert_eq!(t.output(), "fgh");
+    }
+}
@@ -0,0 +1,36 @@
+// the devices the loop waits on, a driver implements EventSource and is
+// registered with Loop::register_source behind a SpinLock, which it shares
+// with the interrupts and the printing, the ops name it by its SourceId
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    // a character of a console
+    Char(char),
+    // a device specific event, like an edge on a pin or a finished transfer
+    Signal(u32),
+    // Ctrl-C on a console in the cooked mode
+    Interrupt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceId(pub(super) usize);
+
+// the MiniUart, registered by Loop::init
+pub const CONSOLE: SourceId = SourceId(0);
+
+pub trait EventSource {
+    // true if poll has something, checked before the core goes to sleep
+    fn ready(&self) -> bool;
+    // the next event, called once per pass of the loop
+    fn poll(&mut self) -> Option<Event>;
+    // false if the device can not take the character now, the devices
+    // without output never take one
+    fn try_put(&mut self, _c: char) -> bool {
+        false
+    }
+    // asks the device to wake up the loop once it can take output, false if
+    // it can not, the loop polls it then
+    fn notify_writable(&mut self) -> bool {
+        false
+    }
+}
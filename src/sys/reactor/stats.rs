@@ -0,0 +1,169 @@
+// what the loop spends its time on, the times are in ticks of the generic
+// timer and shown in microseconds
+
+use crate::asm;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpKind {
+    ReadLine,
+    ReadChar,
+    ReadEvent,
+    PutChar,
+    PutBuffer,
+    Tap,
+    Timer,
+    Channel,
+}
+
+pub const KINDS: usize = 8;
+
+const NAMES: [&str; KINDS] = [
+    "read line",
+    "read char",
+    "read event",
+    "put char",
+    "put buffer",
+    "tap",
+    "timer",
+    "channel",
+];
+
+#[derive(Clone, Copy, Default)]
+pub struct KindStats {
+    pub submitted: u64,
+    // the callback ran, for the taps and the intervals it is never done
+    pub completed: u64,
+    pub cancelled: u64,
+    pub pending: u64,
+    pub max_pending: u64,
+    // from the submit to the callback, of the completed ops
+    pub total_latency: u64,
+    pub max_latency: u64,
+}
+
+impl KindStats {
+    pub fn average_latency(&self) -> u64 {
+        if self.completed == 0 {
+            return 0;
+        }
+        self.total_latency / self.completed
+    }
+    pub(super) fn submit(&mut self) {
+        self.submitted += 1;
+        self.pending += 1;
+        self.max_pending = self.max_pending.max(self.pending);
+    }
+    pub(super) fn complete(&mut self, latency: u64) {
+        self.completed += 1;
+        self.pending -= 1;
+        self.total_latency += latency;
+        self.max_latency = self.max_latency.max(latency);
+    }
+    pub(super) fn cancel(&mut self) {
+        self.cancelled += 1;
+        self.pending -= 1;
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct LoopStats {
+    pub kinds: [KindStats; KINDS],
+    pub passes: u64,
+    // passes that did not call back anything
+    pub empty_passes: u64,
+    // in the passes
+    pub busy: u64,
+    // in wfi
+    pub idle: u64,
+    pub sleeps: u64,
+    // of the deferred queue
+    pub deferred_overflows: usize,
+    pub deferred_high_water: usize,
+}
+
+impl LoopStats {
+    pub fn kind(&self, kind: OpKind) -> &KindStats {
+        &self.kinds[kind as usize]
+    }
+    pub(super) fn kind_mut(&mut self, kind: OpKind) -> &mut KindStats {
+        &mut self.kinds[kind as usize]
+    }
+}
+
+fn micros(ticks: u64) -> u64 {
+    micros_at(ticks, asm::counter_frequency())
+}
+
+// the whole seconds apart, ticks * 1_000_000 overflows after 11 days at 19.2 MHz
+fn micros_at(ticks: u64, frequency: u64) -> u64 {
+    ticks / frequency * 1_000_000 + ticks % frequency * 1_000_000 / frequency
+}
+
+impl core::fmt::Display for LoopStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        writeln!(
+            f,
+            "{:<10} {:>9} {:>9} {:>9} {:>7} {:>7} {:>10} {:>10}",
+            "op", "submitted", "completed", "cancelled", "pending", "max", "avg us", "max us"
+        )?;
+        for (name, k) in NAMES.iter().zip(self.kinds.iter()) {
+            writeln!(
+                f,
+                "{:<10} {:>9} {:>9} {:>9} {:>7} {:>7} {:>10} {:>10}",
+                name,
+                k.submitted,
+                k.completed,
+                k.cancelled,
+                k.pending,
+                k.max_pending,
+                micros(k.average_latency()),
+                micros(k.max_latency)
+            )?;
+        }
+        write!(
+            f,
+            "\
+            passes        {:>10} ({} without progress)\n\
+            busy          {:>10} us\n\
+            idle          {:>10} us ({} sleeps)\n\
+            deferred      {:>10} overflows, {} at most\
+            ",
+            self.passes,
+            self.empty_passes,
+            micros(self.busy),
+            micros(self.idle),
+            self.sleeps,
+            self.deferred_overflows,
+            self.deferred_high_water
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_and_latency() {
+        let mut stats = LoopStats::default();
+        for _ in 0..3 {
+            stats.kind_mut(OpKind::ReadChar).submit();
+        }
+        stats.kind_mut(OpKind::ReadChar).complete(10);
+        stats.kind_mut(OpKind::ReadChar).cancel();
+        stats.kind_mut(OpKind::ReadChar).complete(30);
+        stats.kind_mut(OpKind::ReadChar).submit();
+        let k = stats.kind(OpKind::ReadChar);
+        assert_eq!((k.submitted, k.completed, k.cancelled, k.pending), (4, 2, 1, 1));
+        assert_eq!(k.max_pending, 3);
+        assert_eq!((k.average_latency(), k.max_latency), (20, 30));
+        assert_eq!(stats.kind(OpKind::Timer).average_latency(), 0);
+    }
+
+    #[test]
+    fn micros_of_long_runs() {
+        let frequency = 19_200_000;
+        assert_eq!(micros_at(frequency * 1_000_000_000, frequency), 1_000_000_000_000_000);
+        assert_eq!(micros_at(frequency * 3 / 2, frequency), 1_500_000);
+    }
+}
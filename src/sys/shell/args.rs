@@ -0,0 +1,102 @@
+// splits a command line into its arguments, on whitespace, like a POSIX
+// shell does without the expansions
+//
+// the single quotes keep everything as it is, in the double quotes a
+// backslash escapes a double quote and a backslash, outside of the quotes it
+// escapes any character
+
+use alloc::prelude::v1::*;
+use core::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    UnterminatedQuote(char),
+    TrailingBackslash,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnterminatedQuote(quote) => write!(f, "missing closing {}", quote),
+            ParseError::TrailingBackslash => write!(f, "nothing to escape after \\"),
+        }
+    }
+}
+
+pub fn tokenize(line: &str) -> Result<Vec<String>, ParseError> {
+    let mut args = Vec::new();
+    // "" is an argument as well
+    let mut arg: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if let Some(arg) = arg.take() {
+                    args.push(arg);
+                }
+            },
+            '\'' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err(ParseError::UnterminatedQuote('\'')),
+                    }
+                }
+            },
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) if c == '"' || c == '\\' => arg.push(c),
+                            Some(c) => {
+                                arg.push('\\');
+                                arg.push(c);
+                            },
+                            None => return Err(ParseError::UnterminatedQuote('"')),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(ParseError::UnterminatedQuote('"')),
+                    }
+                }
+            },
+            '\\' => match chars.next() {
+                Some(c) => arg.get_or_insert_with(String::new).push(c),
+                None => return Err(ParseError::TrailingBackslash),
+            },
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(arg) = arg {
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        tokenize(line).unwrap()
+    }
+
+    #[test]
+    fn split() {
+        assert_eq!(args("  gpio  set 17 "), vec!["gpio", "set", "17"]);
+        assert!(args("").is_empty());
+        assert_eq!(args("echo 'a  b' \"c \\\"d\\\" \\n\" e\\ f"), vec!["echo", "a  b", "c \"d\" \\n", "e f"]);
+        assert_eq!(args("x '' \"\" y"), vec!["x", "", "", "y"]);
+        assert_eq!(args("pre'fix'\"ed\""), vec!["prefixed"]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(tokenize("echo 'a"), Err(ParseError::UnterminatedQuote('\'')));
+        assert_eq!(tokenize("echo \"a\\"), Err(ParseError::UnterminatedQuote('"')));
+        assert_eq!(tokenize("echo a\\"), Err(ParseError::TrailingBackslash));
+    }
+}
@@ -0,0 +1,249 @@
+// the commands of the console, the drivers and the subsystems register their
+// own when they are initialized, the shell task only reads the lines and
+// hands them over to execute
+//
+// the commands write their output to a string, the shell task prints it, so
+// they do not wait on the console
+
+pub mod args;
+
+use alloc::prelude::v1::*;
+use core::cell::RefCell;
+use core::fmt;
+use self::args::*;
+
+pub trait Command {
+    fn name(&self) -> &'static str;
+    // a line for help, the usage and what it does
+    fn help(&self) -> &'static str;
+    // the arguments start with the name
+    fn run(&self, args: &[String], out: &mut String) -> Result<(), ShellError>;
+    // the candidates for the argument being typed, the arguments before it
+    // are given, the name is the first one
+    fn complete(&self, _args: &[String]) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+// a command without state
+pub struct Builtin {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub run: fn(&[String], &mut String) -> Result<(), ShellError>,
+}
+
+impl Command for Builtin {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+    fn help(&self) -> &'static str {
+        self.help
+    }
+    fn run(&self, args: &[String], out: &mut String) -> Result<(), ShellError> {
+        (self.run)(args, out)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ShellError {
+    Parse(ParseError),
+    Unknown(String),
+    // the help of the command is shown
+    Usage(&'static str),
+    Failed(String),
+}
+
+impl From<ParseError> for ShellError {
+    fn from(error: ParseError) -> Self {
+        ShellError::Parse(error)
+    }
+}
+
+impl From<fmt::Error> for ShellError {
+    fn from(_: fmt::Error) -> Self {
+        ShellError::Failed("the output could not be written".to_string())
+    }
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShellError::Parse(error) => write!(f, "{}", error),
+            ShellError::Unknown(name) => write!(f, "unknown command: {}, try help", name),
+            ShellError::Usage(help) => write!(f, "usage: {}", help),
+            ShellError::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+// the commands are registered at the start, they can not register others
+// while one of them runs
+pub struct Shell {
+    commands: RefCell<Option<Vec<Box<dyn Command>>>>,
+}
+
+// used by core 0 in thread context only, like the loop
+unsafe impl Sync for Shell {}
+
+impl Shell {
+    pub const fn new() -> Self {
+        Shell {
+            commands: RefCell::new(None),
+        }
+    }
+    pub fn init(&'static self) {
+        *self.commands.borrow_mut() = Some(Vec::new());
+        self.register(Box::new(Help(self)));
+    }
+    // a command with the name of another one replaces it
+    pub fn register(&self, command: Box<dyn Command>) {
+        let mut commands = self.commands.borrow_mut();
+        let commands = commands.as_mut().unwrap();
+        match commands.iter().position(|c| c.name() == command.name()) {
+            Some(i) => commands[i] = command,
+            None => commands.push(command),
+        }
+    }
+    // the empty lines do nothing
+    pub fn execute(&self, line: &str, out: &mut String) -> Result<(), ShellError> {
+        let args = tokenize(line)?;
+        let name = match args.first() {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let commands = self.commands.borrow();
+        match commands.as_ref().unwrap().iter().find(|c| c.name() == name.as_str()) {
+            Some(command) => command.run(&args, out),
+            None => Err(ShellError::Unknown(name.clone())),
+        }
+    }
+    // for the line editor, the names of the commands for the first word and
+    // what the command offers for the others
+    pub fn complete(&self, line: &str, start: usize) -> Vec<String> {
+        let args = match tokenize(&line[..start]) {
+            Ok(args) => args,
+            Err(_) => return Vec::new(),
+        };
+        let commands = self.commands.borrow();
+        let commands = commands.as_ref().unwrap();
+        match args.first() {
+            None => commands.iter().map(|c| c.name().to_string()).collect(),
+            Some(name) => match commands.iter().find(|c| c.name() == name.as_str()) {
+                Some(command) => command.complete(&args),
+                None => Vec::new(),
+            },
+        }
+    }
+    // the names in order
+    pub fn names(&self) -> Vec<&'static str> {
+        let commands = self.commands.borrow();
+        let mut names: Vec<_> = commands.as_ref().unwrap().iter().map(|c| c.name()).collect();
+        names.sort();
+        names
+    }
+    pub fn help(&self, name: &str) -> Option<&'static str> {
+        let commands = self.commands.borrow();
+        commands.as_ref().unwrap().iter().find(|c| c.name() == name).map(|c| c.help())
+    }
+}
+
+// lists the commands of its shell
+struct Help(&'static Shell);
+
+impl Command for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+    fn help(&self) -> &'static str {
+        "help [command], what the commands do"
+    }
+    fn run(&self, args: &[String], out: &mut String) -> Result<(), ShellError> {
+        use core::fmt::Write;
+        let shell = self.0;
+        if args.len() > 2 {
+            return Err(ShellError::Usage(self.help()));
+        }
+        match args.get(1) {
+            Some(name) => match shell.help(name) {
+                Some(help) => writeln!(out, "{}", help)?,
+                None => return Err(ShellError::Unknown(name.clone())),
+            },
+            None => {
+                for name in shell.names() {
+                    writeln!(out, "{:<10} {}", name, shell.help(name).unwrap())?;
+                }
+            },
+        }
+        Ok(())
+    }
+    fn complete(&self, args: &[String]) -> Vec<String> {
+        if args.len() > 1 {
+            return Vec::new();
+        }
+        self.0.names().iter().map(|name| name.to_string()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo(args: &[String], out: &mut String) -> Result<(), ShellError> {
+        out.push_str(&args[1..].join(" "));
+        Ok(())
+    }
+
+    // a shell of its own, the global one is not set up on the host
+    fn shell() -> &'static Shell {
+        let shell = Box::leak(Box::new(Shell::new()));
+        shell.init();
+        shell.register(Box::new(Builtin {
+            name: "echo",
+            help: "echo [words], the words",
+            run: echo,
+        }));
+        shell
+    }
+
+    fn execute(shell: &Shell, line: &str) -> Result<String, ShellError> {
+        let mut out = String::new();
+        shell.execute(line, &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn dispatch() {
+        let shell = shell();
+        assert_eq!(execute(shell, "echo a 'b c'"), Ok("a b c".to_string()));
+        assert_eq!(execute(shell, "  "), Ok(String::new()));
+        let error = execute(shell, "ehco a").unwrap_err();
+        assert_eq!(error, ShellError::Unknown("ehco".to_string()));
+        assert_eq!(error.to_string(), "unknown command: ehco, try help");
+    }
+
+    #[test]
+    fn help() {
+        let shell = shell();
+        assert_eq!(
+            execute(shell, "help"),
+            Ok("echo       echo [words], the words\n\
+                help       help [command], what the commands do\n".to_string())
+        );
+        assert_eq!(execute(shell, "help echo"), Ok("echo [words], the words\n".to_string()));
+        assert_eq!(execute(shell, "help ehco"), Err(ShellError::Unknown("ehco".to_string())));
+        let error = execute(shell, "help echo help").unwrap_err();
+        assert_eq!(error.to_string(), "usage: help [command], what the commands do");
+    }
+
+    #[test]
+    fn complete() {
+        let shell = shell();
+        // in the order they came, the line editor sorts them
+        assert_eq!(shell.complete("", 0), ["help", "echo"]);
+        // the names go to help as its argument
+        assert_eq!(shell.complete("help e", 5), ["echo", "help"]);
+        assert_eq!(shell.complete("help echo e", 10), Vec::<String>::new());
+        assert_eq!(shell.complete("echo e", 5), Vec::<String>::new());
+        assert_eq!(shell.complete("ehco e", 5), Vec::<String>::new());
+    }
+}
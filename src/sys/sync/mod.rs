@@ -0,0 +1,110 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::asm;
+
+// the exclusive monitors only work on cacheable memory, so until the MMU is on
+// and the other cores are running the locks only mask the interrupts
+static MULTI_CORE: AtomicBool = AtomicBool::new(false);
+
+// to be called once the caches are on, before releasing the parked cores
+pub fn enable_multi_core() {
+    MULTI_CORE.store(true, Ordering::SeqCst);
+}
+
+// spin lock that also masks the interrupts on the core holding it, so an
+// interrupt handler cannot deadlock on a lock its core already holds
+pub struct SpinLock<T: ?Sized> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> SpinLock<T> {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+}
+
+// the devices are shared as SpinLock<dyn EventSource>
+impl<T: ?Sized> SpinLock<T> {
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        let daif = asm::irq_save();
+        if MULTI_CORE.load(Ordering::Relaxed) {
+            while self
+                .locked
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                asm::nop();
+            }
+        }
+        SpinLockGuard { lock: self, daif }
+    }
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    daif: u64,
+}
+
+impl<'a, T: ?Sized> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        if MULTI_CORE.load(Ordering::Relaxed) {
+            self.lock.locked.store(false, Ordering::Release);
+        }
+        asm::irq_restore(self.daif);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn contention() {
+        // the host threads stand in for the cores
+        enable_multi_core();
+        let counter = Arc::new(SpinLock::new(0));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let counter = counter.clone();
+                thread::spawn(move || {
+                    for i in 0..10_000 {
+                        let mut count = counter.lock();
+                        let value = *count;
+                        // a chance for the others to run while it is held
+                        if i % 100 == 0 {
+                            thread::yield_now();
+                        }
+                        *count = value + 1;
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*counter.lock(), 40_000);
+    }
+}